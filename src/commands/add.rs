@@ -1,93 +1,261 @@
-use super::{construct_remote_path, load_config, Config};
+use super::{
+    add_ignore_overrides, add_parent_ignores, connect_db, construct_local_path,
+    construct_meta_path, construct_new_remote_path, create_metadir_for_file, is_in_dir,
+    load_config, visit_local_files, Config,
+};
 use crate::db;
 use crate::file_hash;
-use crate::ignore::{parce_ignore, Ignore};
-use futures::prelude::*;
-use rusqlite::Connection;
+use crate::ignore::{parce_repo_ignore, FileMatchExpr};
 
-use std::error::Error;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
 
+/// A command line target of `add`.
+enum Target {
+    /// A file or directory given by its remote path.
+    Path(String),
+    /// A glob pattern relative to the repo root.
+    Glob(FileMatchExpr),
+}
+
+impl Target {
+    fn matches(&self, remote_path: &str, relative_path: &str) -> bool {
+        match self {
+            Target::Path(prefix) => is_in_dir(remote_path, prefix),
+            Target::Glob(expr) => expr.match_file(relative_path),
+        }
+    }
+}
+
+fn is_glob(target: &str) -> bool {
+    target.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+/// Checks if the working file of the remote path exists with exactly the same case,
+/// even on a case-insensitive file system.
+fn exists_exactly(remote_path: &str, config: &Config, local_root: &Path) -> bool {
+    let mut dir = local_root.to_owned();
+    for name in config.relative_path(remote_path).split('/') {
+        let found = std::fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .any(|e| e.file_name() == name)
+            })
+            .unwrap_or(false);
+        if !found {
+            return false;
+        }
+        dir.push(name);
+    }
+    true
+}
+
+/// Stages the changes under `targets`.
+/// New files are staged as `ADD`, modified files as `UPDATE`
+/// and the tracked files missing locally as `REMOVE`.
+/// With `update`, new files are not staged. Without targets, `all` or `update` means the whole repo.
 pub async fn add(
-    target: impl AsRef<Path>,
+    targets: &[impl AsRef<Path>],
+    all: bool,
+    update: bool,
+    ignore_file: Option<&str>,
+    excludes: &[String],
     local_root: impl AsRef<Path>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let ignore_filter = parce_ignore().await?;
     let local_root = local_root.as_ref();
-    let target = target.as_ref();
     let config = load_config(local_root).await.map_err(|_| {
         format!(
             "Directory {} is not a correct dsync repo.",
             local_root.display()
         )
     })?;
-    let conn = db::connect(local_root)?;
+    let mut ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+    add_ignore_overrides(&mut ignore_filter, ignore_file, excludes).await?;
+    let conn = connect_db(&config, local_root)?;
+    let mut files = db::list_files(&conn)?;
+    let placeholders = db::list_placeholders(&conn)?;
+    let mut updates: HashMap<_, _> = db::list_files_to_update(&conn)?
+        .into_iter()
+        .map(|u| (u.path, u.operation))
+        .collect();
 
-    if ignore_filter.is_ignored(&target.to_string_lossy()) {
-        return Ok(());
+    if targets.is_empty() && !all && !update {
+        return Err("Nothing specified, nothing added. Use -A to add everything.".into());
     }
 
-    if target.is_file() {
+    // Local files to be checked with their remote paths.
+    let mut local_files: Vec<(PathBuf, String)> = vec![];
+    let mut scopes = vec![];
+    if targets.is_empty() {
+        local_files = visit_local_files(local_root, &config, local_root, &ignore_filter).await?;
+        scopes.push(Target::Path(config.remote_path.clone()));
+    }
+
+    let mut whole_tree = None;
+    for target in targets.iter() {
+        let target = target.as_ref();
+        let name = target.to_string_lossy();
+        if is_glob(&name) {
+            let expr = FileMatchExpr::compile(&format!("/{}", name.trim_start_matches("./")));
+            if whole_tree.is_none() {
+                whole_tree =
+                    Some(visit_local_files(local_root, &config, local_root, &ignore_filter).await?);
+            }
+            for (path, remote_path) in whole_tree.as_ref().unwrap().iter() {
+                if expr.match_file(config.relative_path(remote_path)) {
+                    local_files.push((path.clone(), remote_path.clone()));
+                }
+            }
+            scopes.push(Target::Glob(expr));
+            continue;
+        }
+
         // remote_path never ends with /.
-        let remote_path = construct_remote_path(target, &config, &local_root)?;
-        db::upsert_file(
-            &conn,
-            &db::FileData::new(remote_path.clone(), file_hash(target).await?.to_vec()),
-        )?;
-        db::add_update(
-            &conn,
-            &db::FileUpdate::new(remote_path, db::FileUpdate::UPDATE),
-        )?;
-    } else if target.is_dir() {
-        add_dir(target, &config, &local_root, &conn, &ignore_filter).await?;
-    } else {
-        return Err(format!(
-            "File {} does not exist or is not either a file or directory.",
-            target.display()
-        )
-        .into());
+        let prefix = construct_new_remote_path(target, &config, local_root)?;
+        let repo_path = config.repo_path(&prefix);
+        let mut target_filter = ignore_filter.clone();
+        add_parent_ignores(&mut target_filter, &repo_path, local_root).await?;
+        if target_filter.is_ignored(&repo_path, target.is_dir()) {
+            continue;
+        }
+        if target.is_file() {
+            local_files.push((target.to_owned(), prefix.clone()));
+        } else if target.is_dir() {
+            local_files.extend(
+                visit_local_files(target, &config, local_root, &ignore_filter)
+                    .await?
+                    .into_iter(),
+            );
+        } else if !files.keys().any(|p| is_in_dir(p, &prefix)) {
+            return Err(format!("File {} does not match any files.", target.display()).into());
+        }
+        scopes.push(Target::Path(prefix));
     }
 
-    Ok(())
-}
+    local_files.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+    local_files.dedup_by(|a, b| a.1 == b.1);
 
-async fn add_dir(
-    target: &Path,
-    config: &Config,
-    local_root: &Path,
-    conn: &Connection,
-    ignore_filter: &Ignore,
-) -> Result<(), Box<dyn Error>> {
-    // List all files under target and upsert.
-    let mut dirs = vec![target.to_owned()];
-
-    while dirs.len() != 0 {
-        let mut new_dirs = vec![];
-        for dir in dirs.iter() {
-            let mut reads = tokio::fs::read_dir(dir).await?;
-            while let Some(entry) = reads.next().await {
-                let path = entry?.path();
-                if ignore_filter.is_ignored(&path.to_string_lossy()) {
-                    continue;
-                }
-                if path.is_file() {
-                    // remote_path never ends with /.
-                    let remote_path = construct_remote_path(&path, &config, &local_root)?;
-                    db::upsert_file(
-                        conn,
-                        &db::FileData::new(remote_path.clone(), file_hash(&path).await?.to_vec()),
-                    )?;
-                    db::add_update(
-                        &conn,
-                        &db::FileUpdate::new(remote_path, db::FileUpdate::UPDATE),
-                    )?;
-                } else if path.is_dir() {
-                    new_dirs.push(path);
+    // Dropbox paths are case-insensitive, so the files differing only in case cannot be staged.
+    // A tracked file renamed locally only in case is staged as an update of the new name.
+    let files_by_lower = db::list_files_by_lower(&conn)?;
+    let mut by_lower: HashMap<String, &str> = HashMap::new();
+    let mut collisions = vec![];
+    let mut renames = vec![];
+    for (_, remote_path) in local_files.iter() {
+        let path_lower = db::path_lower(remote_path);
+        match files_by_lower.get(&path_lower) {
+            Some(file) if file.path == *remote_path => continue,
+            Some(file) if exists_exactly(&file.path, &config, local_root) => {
+                collisions.push((remote_path.clone(), file.path.clone()));
+                continue;
+            }
+            Some(file) => renames.push((file.path.clone(), remote_path.clone())),
+            None => (),
+        }
+        if let Some(other) = by_lower.insert(path_lower, remote_path) {
+            collisions.push((remote_path.clone(), other.to_owned()));
+            collisions.push((other.to_owned(), remote_path.clone()));
+        }
+    }
+    collisions.sort_unstable();
+    collisions.dedup_by(|a, b| a.0 == b.0);
+    for (path, other) in collisions.iter() {
+        println!(
+            "  skipped:  {} (differs from {} only in case)",
+            config.relative_path(path),
+            config.relative_path(other)
+        );
+    }
+    local_files.retain(|(_, remote_path)| !collisions.iter().any(|(p, _)| p == remote_path));
+    renames.retain(|(_, to)| !collisions.iter().any(|(p, _)| p == to));
+
+    for (from, to) in renames.iter() {
+        let meta_path = construct_meta_path(from, &config, local_root);
+        if meta_path.exists() {
+            create_metadir_for_file(to, &config, local_root).await?;
+            fs::rename(&meta_path, construct_meta_path(to, &config, local_root)).await?;
+        }
+        db::rename_file_entry(&conn, from, to)?;
+        if let Some(mut file) = files.remove(from) {
+            file.path = to.clone();
+            files.insert(to.clone(), file);
+        }
+        if let Some(operation) = updates.remove(from) {
+            updates.insert(to.clone(), operation);
+        }
+        println!(
+            "  renamed:  {} -> {}",
+            config.relative_path(from),
+            config.relative_path(to)
+        );
+    }
+
+    let mut staged = vec![];
+    for (local_path, remote_path) in local_files.into_iter() {
+        let tracked = files.get(&remote_path);
+        if update && tracked.is_none() {
+            continue;
+        }
+
+        let hash = file_hash(&local_path).await?.to_vec();
+        let pending = updates.get(&remote_path).copied();
+        // Uploaded again so that Dropbox takes the new case.
+        let renamed = renames.iter().any(|(_, to)| *to == remote_path);
+        let operation = match (tracked, pending) {
+            (Some(file), pending) if file.hash == hash && !renamed => {
+                if pending == Some(db::FileUpdate::REMOVE) {
+                    // The removal is undone.
+                    db::delete_update(&conn, &remote_path)?;
                 }
+                continue;
             }
+            (None, _) | (_, Some(db::FileUpdate::ADD)) => db::FileUpdate::ADD,
+            _ => db::FileUpdate::UPDATE,
+        };
+
+        db::upsert_file(&conn, &db::FileData::new(remote_path.clone(), hash))?;
+        db::add_update(&conn, &db::FileUpdate::new(remote_path.clone(), operation))?;
+        staged.push((operation, remote_path));
+    }
+
+    let mut tracked: Vec<_> = files
+        .keys()
+        .filter(|p| !placeholders.contains_key(*p) && config.is_selected(p))
+        .filter(|p| scopes.iter().any(|s| s.matches(p, config.relative_path(p))))
+        .collect();
+    tracked.sort_unstable();
+    for remote_path in tracked.into_iter() {
+        if construct_local_path(remote_path, &config, local_root).exists() {
+            continue;
         }
 
-        dirs = new_dirs;
+        match updates.get(remote_path).copied() {
+            Some(db::FileUpdate::REMOVE) => (),
+            Some(db::FileUpdate::ADD) => {
+                // Never pushed. Just forget it.
+                db::delete_file_entry(&conn, remote_path)?;
+                println!("  unstaged: {}", config.relative_path(remote_path));
+            }
+            _ => {
+                db::add_update(
+                    &conn,
+                    &db::FileUpdate::new(remote_path.clone(), db::FileUpdate::REMOVE),
+                )?;
+                staged.push((db::FileUpdate::REMOVE, remote_path.clone()));
+            }
+        }
+    }
+
+    staged.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+    for (operation, remote_path) in staged.iter() {
+        let label = match *operation {
+            db::FileUpdate::ADD => "new file:",
+            db::FileUpdate::REMOVE => "deleted: ",
+            _ => "modified:",
+        };
+        println!("  {} {}", label, config.relative_path(remote_path));
     }
 
     Ok(())
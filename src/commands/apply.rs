@@ -0,0 +1,27 @@
+use super::plan::{execute, Plan};
+use super::{connect_db, load_config, save_config};
+
+use std::path::Path;
+
+/// Applies a plan saved by `--plan-out`.
+pub async fn apply(
+    plan_file: impl AsRef<Path>,
+    local_root: impl AsRef<Path>,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let mut config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let plan = Plan::load(plan_file).await?;
+    plan.verify(&config, local_root, token).await?;
+
+    execute(&plan, &mut config, local_root, &conn, token).await?;
+    save_config(&config, local_root).await?;
+
+    Ok(())
+}
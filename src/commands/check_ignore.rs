@@ -0,0 +1,50 @@
+use super::remote::with_parent_ignores;
+use super::{connect_db, construct_new_remote_path, load_config};
+use crate::ignore::{parce_repo_ignore, Rule};
+
+use std::path::Path;
+
+/// Shows the ignore file of the rule relative to the repo root if it is local.
+fn rule_display(rule: &Rule, local_root: &Path) -> String {
+    let prefix = format!("{}/", local_root.display());
+    let source = rule.source.strip_prefix(&prefix).unwrap_or(&rule.source);
+    format!("{}:{}:{}", source, rule.line, rule.pattern)
+}
+
+/// Prints the ignored paths in `targets`. With `verbose`, the rule deciding it is printed as
+/// `SOURCE:LINE:PATTERN<TAB>PATH`, including the negated rules re-including the path.
+/// With `non_matching`, the paths matching no rule are printed as `::<TAB>PATH`.
+pub async fn check_ignore(
+    targets: &[impl AsRef<Path>],
+    verbose: bool,
+    non_matching: bool,
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+    let conn = connect_db(&config, local_root)?;
+
+    for target in targets.iter() {
+        let target = target.as_ref();
+        let remote_path = construct_new_remote_path(target, &config, local_root)?;
+        let repo_path = config.repo_path(&remote_path);
+        let filter = with_parent_ignores(&ignore_filter, &repo_path, &config, &conn, local_root)?;
+
+        match filter.matching_rule(&repo_path, target.is_dir()) {
+            Some(rule) if verbose => {
+                println!("{}\t{}", rule_display(rule, local_root), target.display())
+            }
+            Some(rule) if !rule.negated => println!("{}", target.display()),
+            None if non_matching => println!("::\t{}", target.display()),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use super::merge::clear_conflict;
+use super::{
+    connect_db, construct_local_path, construct_meta_path, construct_new_remote_path, is_in_dir,
+    load_config,
+};
+use crate::db;
+use tokio::fs;
+
+use std::path::Path;
+
+/// Overwrites the working files under `targets` with the `.dsync` copies.
+pub async fn checkout(
+    targets: &[impl AsRef<Path>],
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let files = db::list_files(&conn)?;
+    let staged: Vec<_> = db::list_files_to_update(&conn)?
+        .into_iter()
+        .map(|u| u.path)
+        .collect();
+
+    for target in targets.iter() {
+        let target = target.as_ref();
+        let prefix = construct_new_remote_path(target, &config, local_root)?;
+        let mut paths: Vec<_> = files.keys().filter(|p| is_in_dir(p, &prefix)).collect();
+        paths.sort_unstable();
+
+        if paths.is_empty() {
+            return Err(format!("File {} is not tracked.", target.display()).into());
+        }
+
+        for path in paths.into_iter() {
+            let local_path = construct_local_path(path, &config, local_root);
+            let meta_path = construct_meta_path(path, &config, local_root);
+            if !meta_path.is_file() {
+                println!(
+                    "File {} has no synced copy. Ignoring...",
+                    local_path.display()
+                );
+                continue;
+            }
+
+            if let Some(parent) = local_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::copy(&meta_path, &local_path).await?;
+            clear_conflict(path, &config, local_root, &conn).await?;
+            println!("Checked out {}", local_path.display());
+
+            if staged.contains(path) {
+                println!(
+                    "  - File {} is staged. Use reset command to unstage it.",
+                    local_path.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,55 +1,97 @@
-use super::{create_dirs, download_file, save_config, visit_all_dirs, Config};
-use crate::db;
-use crate::ignore::{parce_ignore, IGNORE_FILE};
+use super::conflicted::plan_link_copies;
+use super::plan::{execute, Action, Plan};
+use super::sparse::normalize_sparse_dir;
+use super::{add_ignore_overrides, save_config, visit_repo_dirs, Config};
+use crate::files::download::download;
+use crate::files::is_api_error;
+use crate::ignore::{parce_global_ignore, Ignore, RepoPath, IGNORE_FILE};
+use crate::{bytes_to_hex_string, content_hash, db};
 use tokio::fs;
 
+use std::collections::HashMap;
 use std::path::Path;
 
+/// Downloads the ignore file at the remote root and adds its rules to `ignore_filter`.
+/// Returns the content with its hash if it exists.
+async fn add_remote_root_ignore(
+    ignore_filter: &mut Ignore,
+    config: &Config,
+    token: &str,
+) -> Result<Option<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
+    let path = config.remote_path_of(IGNORE_FILE);
+    match download(&path, token).await {
+        Ok((_, data)) => {
+            let data = data.to_vec();
+            ignore_filter.add_rules(&RepoPath::root(), &path, data.clone())?;
+            Ok(Some((bytes_to_hex_string(&content_hash(&data)), data)))
+        }
+        Err(e) if is_api_error(&*e, "path/not_found") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Clones the remote directory. The ignore file at the remote root is applied,
+/// together with the global ignore file and the rules of `ignore_file` and `excludes`.
 pub async fn clone(
     remote_path: &str,
     local_root: impl AsRef<Path>,
+    includes: &[String],
+    lazy: bool,
+    ignore_file: Option<&str>,
+    excludes: &[String],
+    dry_run: bool,
     token: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let ignore_filter = parce_ignore().await?;
     let local_root = local_root.as_ref();
 
-    fs::create_dir_all(local_root).await?;
-    let conn = db::connect(local_root)?;
-
     println!(
         "Cloning {} into {} ...",
         remote_path,
         local_root.to_string_lossy()
     );
 
-    let (dirs, files) =
-        visit_all_dirs(remote_path, remote_path.len(), &ignore_filter, token).await?;
+    let mut config = Config::new(remote_path);
+    config.lazy = lazy;
+    if !includes.is_empty() {
+        config.sparse = Some(includes.iter().map(|s| normalize_sparse_dir(s)).collect());
+    }
 
-    let config = Config {
-        remote_path: remote_path.to_owned(),
-        sync_dirs: dirs.clone(),
-    };
+    let mut ignore_filter = parce_global_ignore().await?;
+    let root_ignore = add_remote_root_ignore(&mut ignore_filter, &config, token).await?;
+    add_ignore_overrides(&mut ignore_filter, ignore_file, excludes).await?;
 
-    for dir in dirs.iter() {
-        create_dirs(dir, &config, local_root).await?;
-    }
+    let (dirs, files) = visit_repo_dirs(&config, &ignore_filter, token).await?;
 
+    let mut plan = Plan::new(remote_path);
+    for dir in dirs.into_iter() {
+        plan.push(Action::CreateDir { path: dir });
+    }
     for file in files.iter() {
-        if let Some(ref name) = file.path_display {
-            download_file(name, &config, &local_root, &conn, token).await?;
+        if lazy {
+            if let Some(action) = Action::placeholder(file) {
+                plan.push(action);
+            }
+        } else if let Some(ref path) = file.path_display {
+            plan.push(Action::Download {
+                path: path.clone(),
+                rev: Some(file.rev.clone()),
+                local_hash: None,
+            });
         }
     }
+    plan_link_copies(&mut plan, &files, &HashMap::new());
 
-    let curr_dir = std::env::current_dir()?;
-    let is_curr = std::fs::canonicalize(&curr_dir)? == std::fs::canonicalize(local_root)?;
-    if !is_curr {
-        let mut ignore_file = curr_dir.clone();
-        ignore_file.push(IGNORE_FILE);
-        let mut ignore_file_dst = local_root.to_owned();
-        ignore_file_dst.push(IGNORE_FILE);
-        if ignore_file.exists() {
-            fs::copy(ignore_file, ignore_file_dst).await?;
-        }
+    // Not saved by `--plan-out`, as `apply` needs the repo made by the clone.
+    if !plan.review(dry_run, None).await? {
+        return Ok(());
+    }
+
+    fs::create_dir_all(local_root).await?;
+    let (conn, _) = db::connect(local_root)?;
+    execute(&plan, &mut config, local_root, &conn, token).await?;
+    // Saved like `refresh` does, so that the rules apply until the next pull.
+    if let Some((hash, data)) = root_ignore {
+        db::upsert_remote_ignore(&conn, RepoPath::root().as_str(), &hash, &data)?;
     }
 
     save_config(&config, local_root).await?;
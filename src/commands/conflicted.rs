@@ -0,0 +1,109 @@
+use super::plan::{Action, Plan};
+use crate::files::FileInfo;
+
+use std::collections::{HashMap, HashSet};
+
+const MARKER: &str = "conflicted copy";
+
+/// Returns the name of the original file if `name` is a conflicted copy made by Dropbox,
+/// e.g. `report (Alice's conflicted copy 2020-10-01).docx` for `report.docx`.
+pub(crate) fn original_name(name: &str) -> Option<String> {
+    let marker = name.find(MARKER)?;
+    let open = name[..marker].rfind(" (")?;
+    let owner = &name[open + 2..marker];
+    if !(owner.is_empty() || owner.ends_with("'s ")) {
+        return None;
+    }
+
+    // Find the matching parenthesis. The date may be followed by a number like ` (1)`.
+    let mut depth = 1;
+    let mut close = None;
+    for (i, c) in name[marker..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(marker + i);
+                    break;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let close = close?;
+    Some(format!("{}{}", &name[..open], &name[close + 1..]))
+}
+
+/// Returns the remote path of the original file if `remote_path` is a conflicted copy.
+pub(crate) fn original_path(remote_path: &str) -> Option<String> {
+    let (dir, name) = match remote_path.rfind('/') {
+        Some(i) => (&remote_path[..=i], &remote_path[i + 1..]),
+        None => ("", remote_path),
+    };
+    original_name(name).map(|original| format!("{}{}", dir, original))
+}
+
+/// Plans to link the new conflicted copies in `remote_files` to their originals.
+pub(crate) fn plan_link_copies(
+    plan: &mut Plan,
+    remote_files: &[FileInfo],
+    linked: &HashMap<String, String>,
+) {
+    let remote_paths: HashSet<_> = remote_files
+        .iter()
+        .filter_map(|f| f.path_display.as_ref())
+        .collect();
+
+    let mut paths: Vec<_> = remote_paths.iter().collect();
+    paths.sort_unstable();
+    for path in paths.into_iter() {
+        if linked.contains_key(path.as_str()) {
+            continue;
+        }
+        if let Some(original) = original_path(path) {
+            if remote_paths.contains(&original) {
+                plan.push(Action::LinkCopy {
+                    path: path.to_string(),
+                    original,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn original_name_test() {
+        assert_eq!(
+            original_name("report (Alice's conflicted copy 2020-10-01).docx"),
+            Some("report.docx".to_owned())
+        );
+        assert_eq!(
+            original_name("report (conflicted copy 2020-10-01).docx"),
+            Some("report.docx".to_owned())
+        );
+        assert_eq!(
+            original_name("report (Alice Smith's conflicted copy 2020-10-01 (1)).docx"),
+            Some("report.docx".to_owned())
+        );
+        assert_eq!(
+            original_name("Makefile (Bob's conflicted copy 2020-10-01)"),
+            Some("Makefile".to_owned())
+        );
+        assert_eq!(original_name("report.docx"), None);
+        assert_eq!(original_name("a conflicted copy.txt"), None);
+        assert_eq!(
+            original_name("report (my conflicted copy 2020-10-01.txt"),
+            None
+        );
+        assert_eq!(
+            original_path("/hoge/fuga (conflicted copy 2020-10-01).txt"),
+            Some("/hoge/fuga.txt".to_owned())
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use super::remote::with_parent_ignores;
+use super::{
+    connect_db, construct_local_path, construct_meta_path, construct_new_remote_path,
+    construct_remote_path, create_dirs, create_metadir_for_file, download_file, load_config,
+    save_config, visit_all_dirs, Config,
+};
+use crate::files::copy;
+use crate::files::list_folder::Entry;
+use crate::files::FileInfo;
+use crate::ignore::{parce_repo_ignore, Ignore};
+use crate::{db, file_hash};
+use rusqlite::Connection;
+use tokio::fs;
+
+use std::error::Error;
+use std::path::Path;
+
+pub async fn cp(
+    sources: &[impl AsRef<Path>],
+    dest: impl AsRef<Path>,
+    local_root: impl AsRef<Path>,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let local_root = local_root.as_ref();
+    let dest = dest.as_ref();
+    let mut config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+    let conn = connect_db(&config, local_root)?;
+
+    if sources.len() == 0 {
+        return Err("No source is specified.".into());
+    }
+
+    // Same as cp(1): copy into dest if it is an existing directory.
+    let into_dir = dest.is_dir();
+    if sources.len() > 1 && !into_dir {
+        return Err(format!("Directory {} does not exist.", dest.display()).into());
+    }
+
+    let mut paths = vec![];
+    for source in sources.iter() {
+        let source = source.as_ref();
+        let from_path = construct_remote_path(source, &config, local_root)?;
+        let to_path = if into_dir {
+            let name = source
+                .file_name()
+                .ok_or_else(|| format!("Invalid file name {}", source.display()))?;
+            construct_new_remote_path(dest.join(name), &config, local_root)?
+        } else {
+            construct_new_remote_path(dest, &config, local_root)?
+        };
+        paths.push((from_path, to_path));
+    }
+
+    let results = if paths.len() == 1 {
+        let (ref from_path, ref to_path) = paths[0];
+        println!("Copying {} to {} ...", from_path, to_path);
+        vec![Ok(copy::copy(from_path, to_path, token).await?)]
+    } else {
+        for (from_path, to_path) in paths.iter() {
+            println!("Copying {} to {} ...", from_path, to_path);
+        }
+        copy::copy_batch(&paths, token).await?
+    };
+
+    for ((from_path, to_path), result) in paths.iter().zip(results.into_iter()) {
+        match result {
+            Ok(entry) => {
+                materialize(
+                    from_path,
+                    entry,
+                    &mut config,
+                    local_root,
+                    &conn,
+                    &ignore_filter,
+                    token,
+                )
+                .await?
+            }
+            Err(e) => println!("Failed to copy {} to {}: {}", from_path, to_path, e),
+        }
+    }
+
+    save_config(&config, local_root).await?;
+
+    Ok(())
+}
+
+/// Reproduces a remote copy of `from_path` locally without downloading it if possible.
+async fn materialize(
+    from_path: &str,
+    entry: Entry,
+    config: &mut Config,
+    local_root: &Path,
+    conn: &Connection,
+    ignore_filter: &Ignore,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    match entry {
+        Entry::File(info) => {
+            if let Some(ref name) = info.path_display {
+                let repo_path = config.repo_path(name);
+                let ignore_filter =
+                    with_parent_ignores(ignore_filter, &repo_path, config, conn, local_root)?;
+                if ignore_filter.is_ignored(&repo_path, false) {
+                    return Ok(());
+                }
+                register_parent_dirs(name, config, local_root).await?;
+                materialize_file(from_path, &info, config, local_root, conn, token).await?;
+            }
+        }
+        Entry::Folder { path_display, .. } => {
+            if let Some(name) = path_display {
+                register_parent_dirs(&name, config, local_root).await?;
+                // The remote ignore files in the parent directories are saved by refresh.
+                let repo_path = config.repo_path(&name);
+                let ignore_filter =
+                    with_parent_ignores(ignore_filter, &repo_path, config, conn, local_root)?;
+                let (dirs, files) = visit_all_dirs(&name, config, &ignore_filter, token).await?;
+
+                for dir in dirs.into_iter() {
+                    create_dirs(&dir, config, local_root).await?;
+                    if !config.sync_dirs.contains(&dir) {
+                        config.sync_dirs.push(dir);
+                    }
+                }
+
+                for file in files.iter() {
+                    if let Some(ref path) = file.path_display {
+                        let src = format!("{}{}", from_path, &path[name.len()..]);
+                        materialize_file(&src, file, config, local_root, conn, token).await?;
+                    }
+                }
+            }
+        }
+        Entry::Deleted { .. } => (),
+    }
+
+    Ok(())
+}
+
+async fn materialize_file(
+    from_path: &str,
+    info: &FileInfo,
+    config: &Config,
+    local_root: &Path,
+    conn: &Connection,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let path = match info.path_display {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+    let remote_hash = info.content_hash.as_ref().and_then(|s| hex::decode(s).ok());
+    let src_meta_path = construct_meta_path(from_path, config, local_root);
+    let orig_hash = file_hash(&src_meta_path).await.ok();
+
+    if remote_hash.is_some() && remote_hash.as_deref() == orig_hash.as_ref().map(|h| &h[..]) {
+        let local_path = construct_local_path(path, config, local_root);
+        let meta_path = construct_meta_path(path, config, local_root);
+        println!("Creating file {} from local copy ...", local_path.display());
+        create_metadir_for_file(path, config, local_root).await?;
+        fs::copy(&src_meta_path, &meta_path).await?;
+        fs::copy(&src_meta_path, &local_path).await?;
+        db::upsert_file(
+            conn,
+            &db::FileData::new(path.to_owned(), remote_hash.unwrap()),
+        )?;
+    } else {
+        download_file(path, config, local_root, conn, token).await?;
+    }
+
+    Ok(())
+}
+
+/// Creates the directories between the repo root and `remote_path` if not yet tracked.
+async fn register_parent_dirs(
+    remote_path: &str,
+    config: &mut Config,
+    local_root: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let n_remote_path = config.remote_path.trim_end_matches('/').len();
+    for (i, _) in remote_path.match_indices('/') {
+        if i <= n_remote_path {
+            continue;
+        }
+        let dir = &remote_path[..i];
+        if !config.sync_dirs.iter().any(|d| d == dir) {
+            create_dirs(dir, config, local_root).await?;
+            config.sync_dirs.push(dir.to_owned());
+        }
+    }
+    Ok(())
+}
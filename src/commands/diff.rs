@@ -0,0 +1,268 @@
+use super::encoding::{legacy_encoding_for, Text};
+use super::remote::{fetched_path, load_listing};
+use super::{
+    connect_db, construct_local_path, construct_meta_path, construct_new_remote_path, is_in_dir,
+    load_config,
+};
+use crate::ignore::parce_repo_ignore;
+use crate::{bytes_to_hex_string, db, file_hash};
+use encoding_rs::Encoding;
+use tokio::fs;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const CONTEXT: usize = 3;
+/// The LCS table is not built if it would have more cells than this.
+const MAX_CELLS: usize = 50_000_000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Edit {
+    Same(usize, usize),
+    Remove(usize),
+    Insert(usize),
+}
+
+/// Computes the line edits from `old` to `new` by the longest common subsequence.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * (m + 1) + j] = if old[i] == new[j] {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+            };
+        }
+    }
+
+    let mut edits = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            edits.push(Edit::Same(i, j));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+            edits.push(Edit::Remove(i));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+/// Formats the difference of two texts as unified diff hunks.
+fn unified_diff(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let edits = diff_lines(&old, &new);
+
+    let changed: Vec<_> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Same(..)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut result = String::new();
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(CONTEXT);
+        let mut end = changed[k] + 1;
+        while k < changed.len() && changed[k] <= end + 2 * CONTEXT {
+            end = changed[k] + 1;
+            k += 1;
+        }
+        let end = (end + CONTEXT).min(edits.len());
+
+        // Line numbers of the hunk, counted from 1.
+        let (mut old_start, mut new_start) = (old.len(), new.len());
+        let (mut old_count, mut new_count) = (0, 0);
+        let mut lines = String::new();
+        for edit in edits[start..end].iter() {
+            match *edit {
+                Edit::Same(i, j) => {
+                    old_start = old_start.min(i);
+                    new_start = new_start.min(j);
+                    old_count += 1;
+                    new_count += 1;
+                    lines.push_str(&format!(" {}\n", old[i]));
+                }
+                Edit::Remove(i) => {
+                    old_start = old_start.min(i);
+                    old_count += 1;
+                    lines.push_str(&format!("-{}\n", old[i]));
+                }
+                Edit::Insert(j) => {
+                    new_start = new_start.min(j);
+                    new_count += 1;
+                    lines.push_str(&format!("+{}\n", new[j]));
+                }
+            }
+        }
+        let position = |start: usize, count: usize| {
+            if count == 0 {
+                format!("{},0", start)
+            } else {
+                format!("{},{}", start + 1, count)
+            }
+        };
+        result.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            position(old_start, old_count),
+            position(new_start, new_count)
+        ));
+        result.push_str(&lines);
+    }
+
+    result
+}
+
+fn print_diff(
+    name: &str,
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    legacy: Option<&'static Encoding>,
+) {
+    println!("--- {}", if old.is_some() { name } else { "/dev/null" });
+    println!("+++ {}", if new.is_some() { name } else { "/dev/null" });
+
+    let old = Text::decode(old.unwrap_or_default(), legacy);
+    let new = Text::decode(new.unwrap_or_default(), legacy);
+    match (old, new) {
+        (Some(old), Some(new)) => {
+            let (old, new) = (&old.content, &new.content);
+            let cells = (old.lines().count() + 1) * (new.lines().count() + 1);
+            if cells > MAX_CELLS {
+                println!("Files are too large to compare");
+            } else {
+                print!("{}", unified_diff(old, new));
+            }
+        }
+        _ => println!("Binary files differ"),
+    }
+}
+
+/// Shows the local changes against the `.dsync` copies,
+/// or with `remote`, the fetched remote changes against them.
+pub async fn diff(
+    targets: &[impl AsRef<Path>],
+    remote: bool,
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let files = db::list_files(&conn)?;
+    let placeholders = db::list_placeholders(&conn)?;
+
+    let prefixes = if targets.is_empty() {
+        vec![config.remote_path.clone()]
+    } else {
+        targets
+            .iter()
+            .map(|t| construct_new_remote_path(t, &config, local_root))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let is_target = |path: &str| prefixes.iter().any(|p| is_in_dir(path, p));
+
+    // The new content of each file. `None` means the file is removed.
+    let mut changes = BTreeMap::new();
+    if remote {
+        if !db::has_cursor(&conn)? {
+            return Err("Nothing has been fetched yet. Run fetch command first.".into());
+        }
+        let ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+        let (_, remote_files) = load_listing(&config, &conn, local_root, &ignore_filter)?;
+        let mut remote_paths = std::collections::HashSet::new();
+        for info in remote_files.into_iter() {
+            let (path, hash) = match (info.path_display, info.content_hash) {
+                (Some(path), Some(hash)) => (path, hash),
+                _ => continue,
+            };
+            remote_paths.insert(path.clone());
+            if !is_target(&path) || placeholders.contains_key(&path) {
+                continue;
+            }
+            let meta_path = construct_meta_path(&path, &config, local_root);
+            if file_hash(&meta_path)
+                .await
+                .ok()
+                .map(|h| bytes_to_hex_string(&h))
+                == Some(hash.clone())
+            {
+                continue;
+            }
+            match fs::read(fetched_path(local_root, &hash)).await {
+                Ok(data) => {
+                    changes.insert(path, Some(data));
+                }
+                Err(_) => println!(
+                    "File {} is updated but not fetched. Run fetch command first.",
+                    path
+                ),
+            }
+        }
+        for path in files.keys() {
+            if is_target(path) && config.is_selected(path) && !remote_paths.contains(path) {
+                changes.insert(path.clone(), None);
+            }
+        }
+    } else {
+        for path in files.keys() {
+            if !is_target(path) || placeholders.contains_key(path) {
+                continue;
+            }
+            let local_path = construct_local_path(path, &config, local_root);
+            changes.insert(path.clone(), fs::read(&local_path).await.ok());
+        }
+    }
+
+    for (path, new) in changes.into_iter() {
+        let meta_path = construct_meta_path(&path, &config, local_root);
+        let old = fs::read(&meta_path).await.ok();
+        if old == new {
+            continue;
+        }
+        let legacy = legacy_encoding_for(&config, &path)?;
+        print_diff(
+            config.relative_path(&path),
+            old.as_deref(),
+            new.as_deref(),
+            legacy,
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff_test() {
+        assert_eq!(unified_diff("a\nb\nc\n", "a\nb\nc\n"), "");
+        assert_eq!(
+            unified_diff("a\nb\nc\n", "a\nB\nc\n"),
+            "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n"
+        );
+        assert_eq!(unified_diff("", "a\n"), "@@ -0,0 +1,1 @@\n+a\n");
+
+        let old = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+        let new = old.replace("\n2\n", "\ntwo\n").replace("\n19\n", "\n");
+        assert_eq!(
+            unified_diff(&old, &new),
+            "@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n@@ -16,5 +16,4 @@\n 16\n 17\n 18\n-19\n 20\n"
+        );
+    }
+}
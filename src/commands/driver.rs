@@ -0,0 +1,222 @@
+use super::Config;
+use crate::ignore::FileMatchExpr;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::fs;
+use tokio::process::Command;
+
+use std::error::Error;
+use std::path::Path;
+
+/// How to merge the files changed both locally and remotely.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Driver {
+    /// Line based three-way merge.
+    Text,
+    /// Key by key merge of JSON objects.
+    Json,
+    /// External command with `{base}`, `{local}`, `{remote}` and `{merged}`.
+    /// It must write the result into `{merged}` and exit with non-zero status on conflicts.
+    External(String),
+}
+
+/// A merge driver for the files matching `pattern`.
+/// `driver` is `text`, `json` or an external command.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub(crate) struct DriverRule {
+    pub pattern: String,
+    pub driver: String,
+}
+
+/// Chooses the merge driver for the remote path. The last matching rule wins.
+pub(crate) fn driver_for(config: &Config, remote_path: &str) -> Driver {
+    let path = config.relative_path(remote_path);
+    match config
+        .merge_drivers
+        .iter()
+        .rev()
+        .find(|rule| FileMatchExpr::compile(&rule.pattern).match_file(path))
+        .map(|rule| rule.driver.trim())
+    {
+        None | Some("text") => Driver::Text,
+        Some("json") => Driver::Json,
+        Some(command) => Driver::External(command.to_owned()),
+    }
+}
+
+/// Replaces the placeholders of the command template with the file paths.
+pub(crate) fn fill_command(
+    template: &str,
+    base: &str,
+    local: &str,
+    remote: &str,
+    merged: &str,
+) -> Vec<String> {
+    template
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{base}", base)
+                .replace("{local}", local)
+                .replace("{remote}", remote)
+                .replace("{merged}", merged)
+        })
+        .collect()
+}
+
+/// Runs the external driver on the files. Returns whether it succeeded and the result.
+pub(crate) async fn run_external(
+    command: &str,
+    base: &Path,
+    local: &Path,
+    remote: &Path,
+    merged: &Path,
+) -> Result<(bool, Vec<u8>), Box<dyn Error>> {
+    fs::copy(local, merged).await?;
+    let args = fill_command(
+        command,
+        &base.to_string_lossy(),
+        &local.to_string_lossy(),
+        &remote.to_string_lossy(),
+        &merged.to_string_lossy(),
+    );
+    if args.is_empty() {
+        return Err("The merge driver command is empty.".into());
+    }
+
+    let status = Command::new(&args[0]).args(&args[1..]).status().await?;
+    let data = fs::read(merged).await?;
+    fs::remove_file(merged).await.ok();
+    Ok((status.success(), data))
+}
+
+/// Merges a value changed on both sides. `None` means the key is absent.
+/// The keys changed differently on both sides are collected into `conflicts`.
+fn merge_value(
+    key: &str,
+    base: Option<&Value>,
+    local: Option<&Value>,
+    remote: Option<&Value>,
+    conflicts: &mut Vec<String>,
+) -> Option<Value> {
+    if local == remote || remote == base {
+        return local.cloned();
+    }
+    if local == base {
+        return remote.cloned();
+    }
+
+    match (local, remote) {
+        (Some(Value::Object(local)), Some(Value::Object(remote))) => {
+            let empty = Map::new();
+            let base = match base {
+                Some(Value::Object(base)) => base,
+                _ => &empty,
+            };
+
+            let mut merged = Map::new();
+            let keys = local
+                .keys()
+                .chain(remote.keys().filter(|k| !local.contains_key(*k)));
+            for k in keys {
+                let child = format!("{}/{}", key, k);
+                if let Some(v) =
+                    merge_value(&child, base.get(k), local.get(k), remote.get(k), conflicts)
+                {
+                    merged.insert(k.clone(), v);
+                }
+            }
+            Some(Value::Object(merged))
+        }
+        _ => {
+            conflicts.push(if key.is_empty() {
+                "/".to_owned()
+            } else {
+                key.to_owned()
+            });
+            local.cloned()
+        }
+    }
+}
+
+/// Merges JSON texts key by key.
+/// Returns the merged text, or the conflicting keys. `None` if any of them is not JSON.
+pub(crate) fn merge_json(
+    base: &str,
+    local: &str,
+    remote: &str,
+) -> Option<Result<String, Vec<String>>> {
+    let base: Value = if base.trim().is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(base).ok()?
+    };
+    let local: Value = serde_json::from_str(local).ok()?;
+    let remote: Value = serde_json::from_str(remote).ok()?;
+
+    let mut conflicts = vec![];
+    let merged = merge_value("", Some(&base), Some(&local), Some(&remote), &mut conflicts);
+    if !conflicts.is_empty() {
+        return Some(Err(conflicts));
+    }
+
+    let mut text = serde_json::to_string_pretty(&merged.unwrap_or(Value::Null)).ok()?;
+    text.push('\n');
+    Some(Ok(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_json_test() {
+        let base = r#"{"a": 1, "b": {"c": 2, "d": 3}, "e": 4}"#;
+        let local = r#"{"a": 10, "b": {"c": 2, "d": 3}, "e": 4}"#;
+        let remote = r#"{"a": 1, "b": {"c": 2, "d": 30}, "f": 5}"#;
+        assert_eq!(
+            merge_json(base, local, remote),
+            Some(Ok(
+                "{\n  \"a\": 10,\n  \"b\": {\n    \"c\": 2,\n    \"d\": 30\n  },\n  \"f\": 5\n}\n"
+                    .to_owned()
+            ))
+        );
+
+        let local = r#"{"a": 1, "b": {"c": 20, "d": 3}, "e": 4}"#;
+        let remote = r#"{"a": 1, "b": {"c": 21, "d": 3}, "e": 40}"#;
+        assert_eq!(
+            merge_json(base, local, remote),
+            Some(Err(vec!["/b/c".to_owned()]))
+        );
+
+        assert_eq!(
+            merge_json(base, "[1, 2]", "[1, 3]"),
+            Some(Err(vec!["/".to_owned()]))
+        );
+        assert_eq!(merge_json(base, "{", remote), None);
+    }
+
+    #[test]
+    fn driver_for_test() {
+        let mut config = Config::new("/hoge");
+        config.merge_drivers = vec![
+            DriverRule {
+                pattern: "*.json".to_owned(),
+                driver: "json".to_owned(),
+            },
+            DriverRule {
+                pattern: "*.xml".to_owned(),
+                driver: "xmlmerge {base} {local} {remote} -o {merged}".to_owned(),
+            },
+        ];
+        assert_eq!(driver_for(&config, "/hoge/a.txt"), Driver::Text);
+        assert_eq!(driver_for(&config, "/hoge/conf/a.json"), Driver::Json);
+        assert_eq!(
+            driver_for(&config, "/hoge/a.xml"),
+            Driver::External("xmlmerge {base} {local} {remote} -o {merged}".to_owned())
+        );
+        assert_eq!(
+            fill_command("xmlmerge {base} -o {merged}", "b", "l", "r", "m"),
+            vec!["xmlmerge", "b", "-o", "m"]
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use super::Config;
+use crate::ignore::FileMatchExpr;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use serde::{Deserialize, Serialize};
+
+/// A legacy encoding such as `Shift_JIS` or `windows-1252` for the files matching `pattern`.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub(crate) struct EncodingRule {
+    pub pattern: String,
+    pub encoding: String,
+}
+
+/// Chooses the legacy encoding for the remote path. The last matching rule wins.
+pub(crate) fn legacy_encoding_for(
+    config: &Config,
+    remote_path: &str,
+) -> Result<Option<&'static Encoding>, String> {
+    let path = config.relative_path(remote_path);
+    match config
+        .encodings
+        .iter()
+        .rev()
+        .find(|rule| FileMatchExpr::compile(&rule.pattern).match_file(path))
+    {
+        Some(rule) => Encoding::for_label(rule.encoding.as_bytes())
+            .map(Some)
+            .ok_or_else(|| format!("Unknown encoding {}.", rule.encoding)),
+        None => Ok(None),
+    }
+}
+
+/// A text file decoded for merging. Line endings of `content` are normalized to LF.
+#[derive(Debug)]
+pub(crate) struct Text {
+    pub content: String,
+    encoding: &'static Encoding,
+    bom: bool,
+    crlf: bool,
+}
+
+/// Guesses UTF-16 without BOM from the NUL bytes of ASCII characters.
+fn guess_utf16(data: &[u8]) -> Option<&'static Encoding> {
+    if data.len() < 2 || data.len() % 2 != 0 {
+        return None;
+    }
+
+    let head = &data[..data.len().min(4096)];
+    let pairs = head.len() / 2;
+    let even_nul = head.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd_nul = head.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+    if odd_nul * 5 > pairs * 2 && even_nul * 10 < pairs {
+        Some(UTF_16LE)
+    } else if even_nul * 5 > pairs * 2 && odd_nul * 10 < pairs {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+impl Text {
+    /// Decodes the data by the BOM, as UTF-16, as UTF-8 or by the legacy encoding in this order.
+    /// ASCII data is regarded as in the legacy encoding if any.
+    /// Returns `None` for binary data.
+    pub(crate) fn decode(data: &[u8], legacy: Option<&'static Encoding>) -> Option<Text> {
+        let (encoding, bom_len) = if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+            (encoding, bom_len)
+        } else if let Some(encoding) = guess_utf16(data) {
+            (encoding, 0)
+        } else if let (Some(legacy), true) = (legacy, data.is_ascii()) {
+            (legacy, 0)
+        } else if std::str::from_utf8(data).is_ok() {
+            (UTF_8, 0)
+        } else {
+            (legacy?, 0)
+        };
+
+        let content =
+            encoding.decode_without_bom_handling_and_without_replacement(&data[bom_len..])?;
+        let crlf = content.contains("\r\n");
+        Some(Text {
+            content: content.replace("\r\n", "\n"),
+            encoding,
+            bom: bom_len != 0,
+            crlf,
+        })
+    }
+
+    /// Takes the encoding of the first text in `others` not in UTF-8 if this text is in UTF-8,
+    /// so that a legacy or UTF-16 file is not converted into UTF-8 by a merge.
+    pub(crate) fn prefer_encoding_of(&mut self, others: &[&Text]) {
+        if self.encoding != UTF_8 {
+            return;
+        }
+        if let Some(other) = others.iter().find(|other| other.encoding != UTF_8) {
+            self.encoding = other.encoding;
+            self.bom = other.bom;
+        }
+    }
+
+    /// Encodes `content` in the same way as this text.
+    /// Returns `None` if `content` cannot be represented in the encoding.
+    pub(crate) fn encode(&self, content: &str) -> Option<Vec<u8>> {
+        let content = if self.crlf {
+            content.replace("\r\n", "\n").replace('\n', "\r\n")
+        } else {
+            content.to_owned()
+        };
+
+        let mut data = vec![];
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            // encoding_rs does not encode into UTF-16.
+            if self.bom {
+                data.extend_from_slice(if self.encoding == UTF_16LE {
+                    &[0xFF, 0xFE]
+                } else {
+                    &[0xFE, 0xFF]
+                });
+            }
+            for unit in content.encode_utf16() {
+                data.extend_from_slice(&if self.encoding == UTF_16LE {
+                    unit.to_le_bytes()
+                } else {
+                    unit.to_be_bytes()
+                });
+            }
+        } else {
+            if self.bom {
+                data.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+            }
+            let (encoded, _, had_errors) = self.encoding.encode(&content);
+            if had_errors {
+                return None;
+            }
+            data.extend_from_slice(&encoded);
+        }
+
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::SHIFT_JIS;
+
+    #[test]
+    fn decode_encode_test() {
+        let text = Text::decode(b"hoge\r\nfuga\r\n", None).unwrap();
+        assert_eq!(text.content, "hoge\nfuga\n");
+        assert_eq!(text.encode("piyo\n").unwrap(), b"piyo\r\n");
+
+        let data = [0xEF, 0xBB, 0xBF, b'a', b'\n'];
+        let text = Text::decode(&data, None).unwrap();
+        assert_eq!(text.content, "a\n");
+        assert_eq!(text.encode("a\n").unwrap(), data);
+
+        let data = [0xFF, 0xFE, b'a', 0, b'\n', 0];
+        let text = Text::decode(&data, None).unwrap();
+        assert_eq!(text.content, "a\n");
+        assert_eq!(text.encode("a\n").unwrap(), data);
+
+        let data = [0, b'a', 0, b'b', 0, b'\n'];
+        let text = Text::decode(&data, None).unwrap();
+        assert_eq!(text.content, "ab\n");
+        assert_eq!(text.encode("ab\n").unwrap(), data);
+
+        let (data, _, _) = SHIFT_JIS.encode("日本語\n");
+        assert!(Text::decode(&data, None).is_none());
+        let text = Text::decode(&data, Some(SHIFT_JIS)).unwrap();
+        assert_eq!(text.content, "日本語\n");
+        assert_eq!(
+            text.encode("日本\n").unwrap(),
+            &SHIFT_JIS.encode("日本\n").0[..]
+        );
+        assert!(text.encode("😀\n").is_none());
+
+        assert!(Text::decode(&[0x89, b'P', b'N', b'G', 0xFF], None).is_none());
+    }
+
+    #[test]
+    fn prefer_encoding_of_test() {
+        let ascii = Text::decode(b"abc\n", Some(SHIFT_JIS)).unwrap();
+        assert_eq!(ascii.encoding, SHIFT_JIS);
+
+        let mut local = Text::decode("日本語\n".as_bytes(), Some(SHIFT_JIS)).unwrap();
+        assert_eq!(local.encoding, UTF_8);
+        let (data, _, _) = SHIFT_JIS.encode("日本語\n");
+        let remote = Text::decode(&data, Some(SHIFT_JIS)).unwrap();
+        local.prefer_encoding_of(&[&ascii, &remote]);
+        assert_eq!(
+            local.encode("日本\n").unwrap(),
+            &SHIFT_JIS.encode("日本\n").0[..]
+        );
+
+        let utf16 = Text::decode(&[0xFF, 0xFE, b'a', 0, b'\n', 0], None).unwrap();
+        let mut text = Text::decode(b"a\n", None).unwrap();
+        text.prefer_encoding_of(&[&utf16]);
+        assert_eq!(text.encode("a\n").unwrap(), [0xFF, 0xFE, b'a', 0, b'\n', 0]);
+    }
+
+    #[test]
+    fn legacy_encoding_for_test() {
+        let mut config = Config::new("/hoge");
+        config.encodings = vec![EncodingRule {
+            pattern: "*.csv".to_owned(),
+            encoding: "shift_jis".to_owned(),
+        }];
+        assert_eq!(
+            legacy_encoding_for(&config, "/hoge/a/b.csv"),
+            Ok(Some(SHIFT_JIS))
+        );
+        assert_eq!(legacy_encoding_for(&config, "/hoge/b.txt"), Ok(None));
+
+        config.encodings[0].encoding = "unknown".to_owned();
+        assert!(legacy_encoding_for(&config, "/hoge/b.csv").is_err());
+    }
+}
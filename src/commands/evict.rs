@@ -0,0 +1,68 @@
+use super::{
+    connect_db, construct_local_path, construct_meta_path, construct_new_remote_path, is_in_dir,
+    load_config,
+};
+use crate::{db, file_hash};
+use tokio::fs;
+
+use std::path::Path;
+
+/// Removes the local copies of the files under `targets`, leaving placeholders.
+pub async fn evict(
+    targets: &[impl AsRef<Path>],
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let files = db::list_files(&conn)?;
+    let placeholders = db::list_placeholders(&conn)?;
+    let staged: Vec<_> = db::list_files_to_update(&conn)?
+        .into_iter()
+        .map(|u| u.path)
+        .collect();
+
+    for target in targets.iter() {
+        let prefix = construct_new_remote_path(target, &config, local_root)?;
+        let mut paths: Vec<_> = files
+            .values()
+            .filter(|f| is_in_dir(&f.path, &prefix) && !placeholders.contains_key(&f.path))
+            .collect();
+        paths.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        for file in paths.into_iter() {
+            let local_path = construct_local_path(&file.path, &config, local_root);
+            let meta_path = construct_meta_path(&file.path, &config, local_root);
+            let curr_hash = file_hash(&local_path).await.ok();
+            let orig_hash = file_hash(&meta_path).await.ok();
+
+            if curr_hash.is_none()
+                || curr_hash.as_ref().map(|h| &h[..]) != Some(&file.hash[..])
+                || curr_hash != orig_hash
+                || staged.contains(&file.path)
+            {
+                println!(
+                    "File {} has local changes. Keeping it...",
+                    local_path.display()
+                );
+                continue;
+            }
+
+            let size = fs::metadata(&local_path).await?.len();
+            fs::remove_file(&local_path).await?;
+            fs::remove_file(&meta_path).await?;
+            db::upsert_placeholder(
+                &conn,
+                &db::Placeholder::new(file.path.clone(), None, size as i64),
+            )?;
+            println!("Evicted {}", local_path.display());
+        }
+    }
+
+    Ok(())
+}
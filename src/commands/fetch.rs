@@ -0,0 +1,62 @@
+use super::remote::{fetch_files, load_listing, refresh};
+use super::{
+    connect_db, construct_local_path, construct_new_remote_path, create_metadir_for_file,
+    download_file, is_in_dir, load_config,
+};
+use crate::db;
+use crate::ignore::parce_repo_ignore;
+use tokio::fs;
+
+use std::path::Path;
+
+/// Downloads the placeholder files under `targets`.
+/// Without `targets`, the remote changes are downloaded aside the working tree instead.
+pub async fn fetch(
+    targets: &[impl AsRef<Path>],
+    local_root: impl AsRef<Path>,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let mut conn = connect_db(&config, local_root)?;
+
+    if targets.is_empty() {
+        let ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+        println!("Fetching updates in {}", &config.remote_path);
+        refresh(&config, &mut conn, token).await?;
+        let (_, remote_files) = load_listing(&config, &conn, local_root, &ignore_filter)?;
+        return fetch_files(&remote_files, &config, local_root, &conn, token).await;
+    }
+
+    let placeholders = db::list_placeholders(&conn)?;
+
+    for target in targets.iter() {
+        let target = target.as_ref();
+        let prefix = construct_new_remote_path(target, &config, local_root)?;
+        let mut paths: Vec<_> = placeholders
+            .keys()
+            .filter(|p| is_in_dir(p, &prefix))
+            .collect();
+        paths.sort_unstable();
+
+        if paths.is_empty() {
+            println!("No placeholder found in {}.", target.display());
+        }
+
+        for path in paths.into_iter() {
+            let local_path = construct_local_path(path, &config, local_root);
+            if let Some(parent) = local_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            create_metadir_for_file(path, &config, local_root).await?;
+            download_file(path, &config, local_root, &conn, token).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use super::{construct_meta_path, Config};
+use crate::files::FileInfo;
+use crate::ignore::Ignore;
+use crate::{bytes_to_hex_string, content_hash, db};
+use rusqlite::Connection;
+use tokio::fs;
+
+use std::error::Error;
+use std::path::Path;
+
+/// The files affected by the change of the ignore rules since they were last saved.
+pub(crate) struct IgnoreChanges {
+    hash: String,
+    rules: String,
+    /// The tracked files ignored by the current rules but not by the saved ones.
+    pub ignored: Vec<String>,
+    /// The remote files not tracked yet, ignored by the saved rules but not by the current ones.
+    pub included: Vec<String>,
+}
+
+impl IgnoreChanges {
+    pub(crate) fn report(&self, config: &Config) {
+        println!("Ignore rules changed:");
+        for path in self.ignored.iter() {
+            println!("  now ignored:  {}", config.relative_path(path));
+        }
+        for path in self.included.iter() {
+            println!("  now included: {}", config.relative_path(path));
+        }
+    }
+
+    /// Saves the current rules so that the files are not reported again.
+    pub(crate) fn save(&self, conn: &Connection) -> Result<(), Box<dyn Error>> {
+        db::save_ignore_rules(conn, &self.hash, &self.rules)?;
+        Ok(())
+    }
+}
+
+/// Compares the effective ignore rules `ignore_filter` with the ones saved in the DB.
+/// `remote_files` is the listing filtered by `ignore_filter`.
+/// The overrides like `--exclude` are not regarded as a change of the rules.
+/// Returns `None` if the rules are not changed. If no file is affected by the change,
+/// the rules are saved and `None` is returned as well.
+/// When no rules are saved yet, they are compared with an empty rule set.
+pub(crate) fn detect_ignore_changes(
+    ignore_filter: &Ignore,
+    config: &Config,
+    conn: &Connection,
+    remote_files: &[FileInfo],
+) -> Result<Option<IgnoreChanges>, Box<dyn Error>> {
+    let rules = ignore_filter.rules_text();
+    let hash = bytes_to_hex_string(&content_hash(rules.as_bytes()));
+    let saved = match db::find_ignore_rules(conn) {
+        Ok((saved_hash, _)) if saved_hash == hash => return Ok(None),
+        Ok((_, saved_rules)) => Ignore::from_rules_text(&saved_rules, "<saved>"),
+        Err(_) => Ignore::from_rules_text("", "<saved>"),
+    };
+    let current = Ignore::from_rules_text(&rules, "<current>");
+
+    let files = db::list_files_by_lower(conn)?;
+    let mut ignored: Vec<_> = files
+        .values()
+        .map(|file| &file.path)
+        .filter(|path| config.is_selected(path))
+        .filter(|path| {
+            let path = config.repo_path(path);
+            current.is_ignored(&path, false) && !saved.is_ignored(&path, false)
+        })
+        .cloned()
+        .collect();
+    ignored.sort_unstable();
+
+    let mut included: Vec<_> = remote_files
+        .iter()
+        .filter_map(|info| info.path_display.as_ref())
+        .filter(|path| !files.contains_key(&db::path_lower(path)))
+        .filter(|path| saved.is_ignored(&config.repo_path(path), false))
+        .cloned()
+        .collect();
+    included.sort_unstable();
+
+    let changes = IgnoreChanges {
+        hash,
+        rules,
+        ignored,
+        included,
+    };
+    if changes.ignored.is_empty() && changes.included.is_empty() {
+        changes.save(conn)?;
+        return Ok(None);
+    }
+    Ok(Some(changes))
+}
+
+/// Stops tracking the files but keeps the working files, like `Action::Untrack`.
+pub(crate) async fn untrack_files(
+    paths: &[String],
+    config: &Config,
+    local_root: &Path,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    for path in paths.iter() {
+        let meta_path = construct_meta_path(path, config, local_root);
+        println!("Untracking file {}.", path);
+        fs::remove_file(meta_path).await.ok();
+        db::delete_file_entry(conn, path)?;
+    }
+    Ok(())
+}
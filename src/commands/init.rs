@@ -8,13 +8,6 @@ pub async fn init(
     local_root: impl AsRef<Path>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     db::connect(&local_root)?;
-    save_config(
-        &Config {
-            remote_path: remote_path.to_owned(),
-            sync_dirs: vec![],
-        },
-        &local_root,
-    )
-    .await?;
+    save_config(&Config::new(remote_path), &local_root).await?;
     Ok(())
 }
@@ -0,0 +1,80 @@
+use super::{construct_local_path, construct_meta_path, construct_target_path, load_config};
+use crate::file_hash;
+use crate::files::list_revisions::list_revisions;
+
+use std::path::Path;
+
+pub async fn log(
+    target: &str,
+    limit: u32,
+    local_root: impl AsRef<Path>,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let remote_path = construct_target_path(target, &config, local_root)?;
+    let revisions = list_revisions(&remote_path, limit, None, token).await?;
+
+    if revisions.is_deleted {
+        println!(
+            "The file is deleted at {}.",
+            revisions
+                .server_deleted
+                .as_deref()
+                .unwrap_or("unknown time")
+        );
+    }
+
+    // When looked up by id, the path is only known from the revisions.
+    let path = revisions
+        .entries
+        .iter()
+        .find_map(|e| e.path_display.as_ref())
+        .filter(|p| p.starts_with(&config.remote_path));
+    let (orig_hash, curr_hash) = if let Some(path) = path {
+        (
+            file_hash(construct_meta_path(path, &config, local_root))
+                .await
+                .ok(),
+            file_hash(construct_local_path(path, &config, local_root))
+                .await
+                .ok(),
+        )
+    } else {
+        (None, None)
+    };
+
+    for entry in revisions.entries.iter() {
+        let hash = entry
+            .content_hash
+            .as_ref()
+            .and_then(|s| hex::decode(s).ok());
+        let mut marks = vec![];
+        if hash.is_some() && hash.as_deref() == orig_hash.as_ref().map(|h| &h[..]) {
+            marks.push("base");
+        }
+        if hash.is_some() && hash.as_deref() == curr_hash.as_ref().map(|h| &h[..]) {
+            marks.push("working");
+        }
+
+        println!(
+            "{} {} {:>10} {}{}",
+            entry.rev,
+            entry.server_modified,
+            entry.size,
+            entry.content_hash.as_deref().unwrap_or("-"),
+            if marks.is_empty() {
+                "".to_owned()
+            } else {
+                format!(" ({})", marks.join(", "))
+            }
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,417 @@
+use super::driver::{driver_for, merge_json, run_external, Driver};
+use super::encoding::{legacy_encoding_for, Text};
+use super::remote::download_remote;
+use super::{construct_local_path, construct_meta_path, create_metadir_for_file, Config};
+use crate::ignore::FileMatchExpr;
+use crate::{content_hash, db};
+use diffmerge::merge;
+use encoding_rs::Encoding;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The directory keeping the versions of the conflicted files for `mergetool`.
+pub const MERGE_DIR: &str = ".dsync_merge";
+
+/// How to handle a file changed both locally and remotely.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Strategy {
+    /// Keep the local file.
+    Ours,
+    /// Take the remote file.
+    Theirs,
+    /// Keep the local file and save the remote file next to it.
+    Both,
+    /// Merge text files. Binary files are handled as `Both`.
+    Merge,
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::Merge
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Strategy::Ours => "ours",
+            Strategy::Theirs => "theirs",
+            Strategy::Both => "both",
+            Strategy::Merge => "merge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ours" => Ok(Strategy::Ours),
+            "theirs" => Ok(Strategy::Theirs),
+            "both" => Ok(Strategy::Both),
+            "merge" => Ok(Strategy::Merge),
+            _ => Err(format!("Unknown strategy {}.", s)),
+        }
+    }
+}
+
+/// A default strategy for the files matching `pattern`, written like `.dsyncignore`.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub(crate) struct StrategyRule {
+    pub pattern: String,
+    pub strategy: Strategy,
+}
+
+/// Chooses the strategy for the remote path. The last matching rule wins.
+pub(crate) fn strategy_for(config: &Config, remote_path: &str) -> Strategy {
+    let path = config.relative_path(remote_path);
+    config
+        .strategies
+        .iter()
+        .rev()
+        .find(|rule| FileMatchExpr::compile(&rule.pattern).match_file(path))
+        .map(|rule| rule.strategy)
+        .unwrap_or_default()
+}
+
+/// The path where a version (`BASE`, `LOCAL` or `REMOTE`) of the conflicted file is kept.
+pub(crate) fn stash_path(
+    remote_path: &str,
+    version: &str,
+    config: &Config,
+    local_root: &Path,
+) -> PathBuf {
+    let mut merge_root = local_root.to_owned();
+    merge_root.push(MERGE_DIR);
+    let mut path = construct_local_path(remote_path, config, &merge_root);
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            version,
+            ext.to_string_lossy()
+        ),
+        _ => format!(
+            "{}.{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            version
+        ),
+    };
+    path.set_file_name(name);
+    path
+}
+
+async fn stash_versions(
+    remote_path: &str,
+    versions: &[(&str, &[u8])],
+    config: &Config,
+    local_root: &Path,
+) -> Result<(), Box<dyn Error>> {
+    for (version, data) in versions.iter() {
+        let path = stash_path(remote_path, version, config, local_root);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, data).await?;
+    }
+    Ok(())
+}
+
+/// Forgets the conflict of the file together with the kept versions.
+pub(crate) async fn clear_conflict(
+    remote_path: &str,
+    config: &Config,
+    local_root: &Path,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    for version in ["BASE", "LOCAL", "REMOTE"].iter() {
+        fs::remove_file(stash_path(remote_path, version, config, local_root))
+            .await
+            .ok();
+    }
+    db::delete_conflict(conn, remote_path)?;
+    Ok(())
+}
+
+fn conflict_copy_path(local_path: &Path) -> PathBuf {
+    const CONFLICT_SUFFIX: &str = "CONFLICTED";
+    let mut conflict_path = local_path.to_owned();
+    if let Some(ext) = local_path.extension() {
+        let mut new_ext = std::ffi::OsString::from(CONFLICT_SUFFIX);
+        new_ext.push(".");
+        new_ext.push(ext);
+        conflict_path.set_extension(new_ext);
+    } else {
+        conflict_path.set_extension(CONFLICT_SUFFIX);
+    }
+    conflict_path
+}
+
+/// Merges the texts in their encodings. The result is encoded like the local file,
+/// unless the local file is in UTF-8 and the others are not.
+/// With `json`, JSON files are merged key by key and the other files line by line.
+/// If the same key is changed on both sides, the merge fails and the local data is kept.
+/// Returns whether the merge succeeded together with the result,
+/// or `None` if any of them is not a text.
+fn merge_texts(
+    orig_data: &[u8],
+    repo_data: &[u8],
+    remote_data: &[u8],
+    legacy: Option<&'static Encoding>,
+    json: bool,
+) -> Option<(bool, Vec<u8>)> {
+    let orig = Text::decode(orig_data, legacy)?;
+    let mut repo = Text::decode(repo_data, legacy)?;
+    let remote = Text::decode(remote_data, legacy)?;
+    repo.prefer_encoding_of(&[&remote, &orig]);
+
+    if json {
+        match merge_json(&orig.content, &repo.content, &remote.content) {
+            Some(Ok(merged)) => return Some((true, repo.encode(&merged)?)),
+            Some(Err(keys)) => {
+                println!("  - Conflicting keys: {}", keys.join(", "));
+                return Some((false, repo_data.to_vec()));
+            }
+            None => println!("  - Not a valid JSON. Merging as text..."),
+        }
+    }
+
+    let mut merged = merge(&orig.content, &repo.content, &remote.content);
+
+    // hash?
+    merged.set_names("local data", "remote data");
+
+    let merge_data = repo.encode(&format!("{}", merged))?;
+    Some((merged.is_successful(), merge_data))
+}
+
+/// Applies the remote changes to the locally modified file by `strategy`.
+pub(crate) async fn merge_file(
+    path: &str,
+    strategy: Strategy,
+    config: &Config,
+    local_root: &Path,
+    conn: &Connection,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let local_path = construct_local_path(path, config, local_root);
+    let meta_path = construct_meta_path(path, config, local_root);
+
+    println!(
+        "Conflict found in file {}. Resolving by {}...",
+        local_path.display(),
+        strategy
+    );
+    let (remote_info, remote_data) = download_remote(path, local_root, conn, token).await?;
+    let remote_hash = remote_info
+        .content_hash
+        .and_then(|s| hex::decode(s).ok())
+        .unwrap_or_else(|| content_hash(&remote_data).to_vec());
+    let repo_data = fs::read(&local_path).await?;
+    let orig_data = fs::read(&meta_path).await.unwrap_or_default();
+
+    let merged = if strategy == Strategy::Merge {
+        match driver_for(config, path) {
+            Driver::External(command) => {
+                stash_versions(
+                    path,
+                    &[
+                        ("BASE", &orig_data),
+                        ("LOCAL", &repo_data),
+                        ("REMOTE", &remote_data),
+                    ],
+                    config,
+                    local_root,
+                )
+                .await?;
+                let versions: Vec<_> = ["BASE", "LOCAL", "REMOTE", "MERGED"]
+                    .iter()
+                    .map(|v| stash_path(path, v, config, local_root))
+                    .collect();
+                match run_external(
+                    &command,
+                    &versions[0],
+                    &versions[1],
+                    &versions[2],
+                    &versions[3],
+                )
+                .await
+                {
+                    Ok(result) => Some(result),
+                    Err(e) => {
+                        println!("  - Failed to run the merge driver: {}", e);
+                        Some((false, repo_data.clone()))
+                    }
+                }
+            }
+            driver => {
+                let legacy = legacy_encoding_for(config, path)?;
+                merge_texts(
+                    &orig_data,
+                    &repo_data,
+                    &remote_data,
+                    legacy,
+                    driver == Driver::Json,
+                )
+            }
+        }
+    } else {
+        None
+    };
+    match (strategy, merged) {
+        (Strategy::Ours, _) => {
+            println!("  - Keeping the local file.");
+            db::upsert_file(
+                conn,
+                &db::FileData::new(path.to_owned(), content_hash(&repo_data).to_vec()),
+            )?;
+            clear_conflict(path, config, local_root, conn).await?;
+        }
+        (Strategy::Theirs, _) => {
+            println!("  - Taking the remote file.");
+            fs::write(&local_path, &remote_data).await?;
+            db::upsert_file(conn, &db::FileData::new(path.to_owned(), remote_hash))?;
+            clear_conflict(path, config, local_root, conn).await?;
+        }
+        (Strategy::Merge, Some((true, merge_data))) => {
+            fs::write(&local_path, &merge_data).await?;
+            db::upsert_file(
+                conn,
+                &db::FileData::new(path.to_owned(), content_hash(&merge_data).to_vec()),
+            )?;
+            clear_conflict(path, config, local_root, conn).await?;
+            println!("  - Merged sucessfully!");
+        }
+        (Strategy::Merge, Some((false, merge_data))) => {
+            stash_versions(
+                path,
+                &[
+                    ("BASE", &orig_data),
+                    ("LOCAL", &repo_data),
+                    ("REMOTE", &remote_data),
+                ],
+                config,
+                local_root,
+            )
+            .await?;
+            fs::write(&local_path, &merge_data).await?;
+            db::upsert_conflict(
+                conn,
+                &db::Conflict::new(path.to_owned(), db::Conflict::MARKERS, None),
+            )?;
+            println!("  - Failed to merge...");
+        }
+        (Strategy::Both, _) | (Strategy::Merge, None) => {
+            let conflict_path = conflict_copy_path(&local_path);
+            if strategy == Strategy::Merge {
+                println!("  - Cannot merge binary files.");
+            }
+            println!(
+                "  - Downloading remote file at {} ...",
+                conflict_path.display()
+            );
+            stash_versions(
+                path,
+                &[
+                    ("BASE", &orig_data),
+                    ("LOCAL", &repo_data),
+                    ("REMOTE", &remote_data),
+                ],
+                config,
+                local_root,
+            )
+            .await?;
+            // change the local hash to the remote value
+            db::upsert_file(conn, &db::FileData::new(path.to_owned(), remote_hash))?;
+            fs::write(&conflict_path, &remote_data).await?;
+            db::upsert_conflict(
+                conn,
+                &db::Conflict::new(
+                    path.to_owned(),
+                    db::Conflict::COPY,
+                    Some(conflict_path.to_string_lossy().into_owned()),
+                ),
+            )?;
+        }
+    }
+
+    // Update original value
+    create_metadir_for_file(path, config, local_root).await?;
+    fs::write(&meta_path, &remote_data).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategy_for_test() {
+        let mut config = Config::new("/hoge");
+        assert_eq!(strategy_for(&config, "/hoge/fuga.txt"), Strategy::Merge);
+
+        config.strategies = vec![
+            StrategyRule {
+                pattern: "*.png".to_owned(),
+                strategy: Strategy::Theirs,
+            },
+            StrategyRule {
+                pattern: "/local/".to_owned(),
+                strategy: Strategy::Ours,
+            },
+            StrategyRule {
+                pattern: "/local/*.png".to_owned(),
+                strategy: Strategy::Both,
+            },
+        ];
+        assert_eq!(strategy_for(&config, "/hoge/fuga.txt"), Strategy::Merge);
+        assert_eq!(strategy_for(&config, "/hoge/a/b.png"), Strategy::Theirs);
+        assert_eq!(strategy_for(&config, "/hoge/local/b.txt"), Strategy::Ours);
+        assert_eq!(strategy_for(&config, "/hoge/local/b.png"), Strategy::Both);
+        assert_eq!("theirs".parse::<Strategy>(), Ok(Strategy::Theirs));
+        assert!("mine".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn stash_path_test() {
+        let config = Config::new("/hoge");
+        let root = Path::new("/tmp/repo");
+        assert_eq!(
+            stash_path("/hoge/a/b.txt", "BASE", &config, root),
+            Path::new("/tmp/repo/.dsync_merge/a/b.BASE.txt")
+        );
+        assert_eq!(
+            stash_path("/hoge/Makefile", "LOCAL", &config, root),
+            Path::new("/tmp/repo/.dsync_merge/Makefile.LOCAL")
+        );
+    }
+
+    #[test]
+    fn merge_texts_json_test() {
+        let orig = br#"{"a": 1, "b": 1}"#;
+        let local = br#"{"a": 2, "b": 1}"#;
+        let remote = br#"{"a": 1, "b": 2}"#;
+        let (ok, data) = merge_texts(orig, local, remote, None, true).unwrap();
+        assert!(ok);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&data).unwrap(),
+            serde_json::json!({"a": 2, "b": 2})
+        );
+
+        let remote = br#"{"a": 3, "b": 1}"#;
+        assert_eq!(
+            merge_texts(orig, local, remote, None, true),
+            Some((false, local.to_vec()))
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use super::driver::fill_command;
+use super::merge::{clear_conflict, stash_path};
+use super::resolve::has_conflict_markers;
+use super::{connect_db, construct_local_path, construct_new_remote_path, is_in_dir, load_config};
+use crate::db;
+use tokio::fs;
+use tokio::process::Command;
+
+use std::path::Path;
+
+/// Makes the command line for the tool.
+/// `tool` is either a known tool name or a command with `{base}`, `{local}`, `{remote}`
+/// and `{merged}` to be replaced with the file paths.
+fn tool_command(tool: &str, base: &str, local: &str, remote: &str, merged: &str) -> Vec<String> {
+    let template = match tool {
+        "meld" => "meld {local} {base} {remote} --output {merged}",
+        "kdiff3" => "kdiff3 {base} {local} {remote} -o {merged}",
+        "vimdiff" => "vimdiff {merged} {local} {base} {remote}",
+        tool => tool,
+    };
+
+    fill_command(template, base, local, remote, merged)
+}
+
+/// Runs the external merge tool for the conflicted files under `targets`
+/// and marks them as resolved if the result has no conflict markers.
+pub async fn mergetool(
+    targets: &[impl AsRef<Path>],
+    tool: Option<&str>,
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let tool = tool
+        .map(|t| t.to_owned())
+        .or_else(|| config.merge_tool.clone())
+        .filter(|t| !t.trim().is_empty())
+        .ok_or_else(|| {
+            "No merge tool is configured. Use --tool or set merge_tool in the repo config."
+                .to_owned()
+        })?;
+    let conn = connect_db(&config, local_root)?;
+
+    let prefixes = if targets.is_empty() {
+        vec![config.remote_path.clone()]
+    } else {
+        targets
+            .iter()
+            .map(|t| construct_new_remote_path(t, &config, local_root))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let conflicts: Vec<_> = db::list_conflicts(&conn)?
+        .into_iter()
+        .filter(|c| c.kind == db::Conflict::MARKERS || c.kind == db::Conflict::COPY)
+        .filter(|c| prefixes.iter().any(|p| is_in_dir(&c.path, p)))
+        .collect();
+
+    if conflicts.is_empty() {
+        println!("No conflict to merge.");
+    }
+
+    for conflict in conflicts.into_iter() {
+        let local_path = construct_local_path(&conflict.path, &config, local_root);
+        let versions: Vec<_> = ["BASE", "LOCAL", "REMOTE"]
+            .iter()
+            .map(|v| stash_path(&conflict.path, v, &config, local_root))
+            .collect();
+        if versions.iter().any(|v| !v.is_file()) {
+            println!(
+                "The versions of {} are not kept. Ignoring...",
+                local_path.display()
+            );
+            continue;
+        }
+
+        let args = tool_command(
+            &tool,
+            &versions[0].to_string_lossy(),
+            &versions[1].to_string_lossy(),
+            &versions[2].to_string_lossy(),
+            &local_path.to_string_lossy(),
+        );
+        println!("Merging {} by {} ...", local_path.display(), args[0]);
+        let status = Command::new(&args[0]).args(&args[1..]).status().await?;
+        if !status.success() {
+            println!("  - The merge tool failed. Ignoring...");
+            continue;
+        }
+
+        let merged = fs::read(&local_path).await?;
+        if has_conflict_markers(&String::from_utf8_lossy(&merged)) {
+            println!("  - Conflict markers remain. Ignoring...");
+            continue;
+        }
+
+        if let Some(ref copy_path) = conflict.copy_path {
+            fs::remove_file(copy_path).await.ok();
+        }
+        clear_conflict(&conflict.path, &config, local_root, &conn).await?;
+        println!("  - Resolved. Use add command to stage it.");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_command_test() {
+        assert_eq!(
+            tool_command("kdiff3", "b", "l", "r", "m"),
+            vec!["kdiff3", "b", "l", "r", "-o", "m"]
+        );
+        assert_eq!(
+            tool_command(
+                "mytool --base={base} {local} {remote} {merged}",
+                "b",
+                "l",
+                "r",
+                "m"
+            ),
+            vec!["mytool", "--base=b", "l", "r", "m"]
+        );
+    }
+}
@@ -1,33 +1,148 @@
 use crate::files::delete;
-use crate::files::download;
 use crate::files::list_folder::{self, Entry};
-use crate::files::FileInfo;
-use crate::ignore::Ignore;
+use crate::files::{is_api_error, FileInfo};
+use crate::ignore::{Ignore, RepoPath, IGNORE_FILE};
 use crate::{db, files};
 
+use futures::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 
 pub mod add;
+pub mod apply;
+pub mod check_ignore;
+pub mod checkout;
 pub mod clone;
+pub(crate) mod conflicted;
+pub mod cp;
+pub mod diff;
+pub(crate) mod driver;
+pub(crate) mod encoding;
+pub mod evict;
+pub mod fetch;
+pub(crate) mod ignore_changes;
 pub mod init;
+pub mod log;
+pub(crate) mod merge;
+pub mod mergetool;
+pub(crate) mod plan;
 pub mod pull;
-// TODO push, rm, auth?
+pub mod push;
+pub(crate) mod remote;
+pub mod reset;
+pub mod resolve;
+pub mod restore;
+pub mod rollback;
+pub mod sparse;
+pub mod status;
+// TODO rm, auth?
 
 pub const CONF_DIR: &str = ".dsync";
 pub const CONF_FILE: &str = ".dsyncconfig";
+/// The directory keeping the remote files downloaded by `fetch`.
+pub const FETCH_DIR: &str = ".dsync_fetch";
 
 #[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
 pub(crate) struct Config {
     pub remote_path: String,
     pub sync_dirs: Vec<String>,
+    /// Subtrees to be synchronized relative to the repo root, e.g. `docs/api`.
+    /// `None` means the whole repo.
+    #[serde(default)]
+    pub sparse: Option<Vec<String>>,
+    /// New remote files are only recorded as placeholders without being downloaded.
+    #[serde(default)]
+    pub lazy: bool,
+    /// Default strategies for the conflicted files by pattern.
+    #[serde(default)]
+    pub strategies: Vec<merge::StrategyRule>,
+    /// External tool used by `mergetool`.
+    #[serde(default)]
+    pub merge_tool: Option<String>,
+    /// Merge drivers by pattern.
+    #[serde(default)]
+    pub merge_drivers: Vec<driver::DriverRule>,
+    /// Legacy encodings of the text files by pattern.
+    #[serde(default)]
+    pub encodings: Vec<encoding::EncodingRule>,
+    /// Conflicted copies made by Dropbox are handled as conflicts of their originals.
+    #[serde(default)]
+    pub fold_conflicted_copies: bool,
+    /// The rules of `.gitignore` at the repo root are applied as well.
+    #[serde(default)]
+    pub import_gitignore: bool,
 }
 
 impl Config {
+    pub(crate) fn new(remote_path: &str) -> Self {
+        Config {
+            remote_path: remote_path.to_owned(),
+            sync_dirs: vec![],
+            sparse: None,
+            lazy: false,
+            strategies: vec![],
+            merge_tool: None,
+            merge_drivers: vec![],
+            encodings: vec![],
+            fold_conflicted_copies: false,
+            import_gitignore: false,
+        }
+    }
+
     fn remote_to_local_path<'a>(&self, path: &'a str) -> &'a str {
-        &path[self.remote_path.len()..]
+        let root = self.remote_path.trim_end_matches('/');
+        if is_in_dir(path, root) {
+            &path[root.len()..]
+        } else {
+            path
+        }
+    }
+
+    /// Converts a remote path into the path relative to the repo root.
+    pub(crate) fn relative_path<'a>(&self, remote_path: &'a str) -> &'a str {
+        self.remote_to_local_path(remote_path)
+            .trim_start_matches('/')
+    }
+
+    /// Converts a remote path into the path matched against the ignore rules.
+    pub(crate) fn repo_path(&self, remote_path: &str) -> RepoPath {
+        RepoPath::new(self.relative_path(remote_path))
+    }
+
+    /// Converts a path relative to the repo root into the remote path.
+    pub(crate) fn remote_path_of(&self, relative_path: &str) -> String {
+        let relative_path = relative_path.trim_matches('/');
+        let root = self.remote_path.trim_end_matches('/');
+        if relative_path == "" {
+            root.to_owned()
+        } else {
+            format!("{}/{}", root, relative_path)
+        }
+    }
+
+    /// The remote directories to be synchronized.
+    pub(crate) fn sync_roots(&self) -> Vec<String> {
+        if let Some(ref sparse) = self.sparse {
+            sparse.iter().map(|s| self.remote_path_of(s)).collect()
+        } else {
+            vec![self.remote_path.clone()]
+        }
+    }
+
+    /// Checks if the remote path is inside the subtrees to be synchronized.
+    pub(crate) fn is_selected(&self, remote_path: &str) -> bool {
+        self.sync_roots()
+            .iter()
+            .any(|root| is_in_dir(remote_path, root))
+    }
+
+    /// Checks if the remote directory contains any of the subtrees to be synchronized.
+    pub(crate) fn is_sparse_ancestor(&self, remote_dir: &str) -> bool {
+        self.sync_roots()
+            .iter()
+            .any(|root| is_in_dir(root, remote_dir))
     }
 }
 
@@ -51,6 +166,21 @@ pub(crate) async fn load_config(root: impl AsRef<Path>) -> Result<Config, Box<dy
     Ok(serde_json::from_str(std::str::from_utf8(&data)?)?)
 }
 
+/// Opens the DB of the repo and reports the files no longer tracked by its upgrade.
+pub(crate) fn connect_db(
+    config: &Config,
+    local_root: &Path,
+) -> Result<rusqlite::Connection, Box<dyn Error>> {
+    let (conn, dropped) = db::connect(local_root)?;
+    for path in dropped.iter() {
+        println!(
+            "{} differs from another tracked file only in case. It is no longer tracked.",
+            config.relative_path(path)
+        );
+    }
+    Ok(conn)
+}
+
 pub(crate) fn construct_local_path(
     remote_path: &str,
     config: &Config,
@@ -93,6 +223,62 @@ pub(crate) fn construct_remote_path(
     }
 }
 
+/// Same as `construct_remote_path`, but `local_path` itself need not exist yet.
+pub(crate) fn construct_new_remote_path(
+    local_path: impl AsRef<Path>,
+    config: &Config,
+    local_root: impl AsRef<Path>,
+) -> Result<String, Box<dyn Error>> {
+    let local_path = local_path.as_ref();
+    if local_path.exists() {
+        return construct_remote_path(local_path, config, local_root);
+    }
+
+    let name = local_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("Invalid file name {}", local_path.display()))?;
+    let parent = match local_path.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    let parent = construct_remote_path(parent, config, local_root)?;
+
+    if parent.ends_with("/") {
+        Ok(format!("{}{}", parent, name))
+    } else {
+        Ok(format!("{}/{}", parent, name))
+    }
+}
+
+/// Converts a command line target into a remote path.
+/// A Dropbox file id (`id:...`) is passed through as is.
+pub(crate) fn construct_target_path(
+    target: &str,
+    config: &Config,
+    local_root: impl AsRef<Path>,
+) -> Result<String, Box<dyn Error>> {
+    if target.starts_with("id:") {
+        Ok(target.to_owned())
+    } else {
+        construct_new_remote_path(target, config, local_root)
+    }
+}
+
+/// Checks if the remote path `path` is `dir` itself or under `dir`.
+/// As on Dropbox, the paths are compared ignoring case.
+/// If true, `path` can be sliced at the length of `dir` without its trailing `/`.
+pub(crate) fn is_in_dir(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    match (path.get(..dir.len()), path.get(dir.len()..)) {
+        (Some(head), Some(rest)) => {
+            db::path_lower(head) == db::path_lower(dir)
+                && (rest.is_empty() || rest.starts_with('/'))
+        }
+        _ => false,
+    }
+}
+
 pub(crate) fn construct_meta_path(
     remote_path: &str,
     config: &Config,
@@ -145,6 +331,7 @@ pub(crate) async fn upsert_metadata(
         };
 
         db::upsert_file(connection, &metadata)?;
+        db::delete_placeholder(connection, &metadata.path)?;
     }
     Ok(())
 }
@@ -163,12 +350,21 @@ async fn read_dir(
     Ok(entries)
 }
 
+/// Checks if the remote path is an ignore file.
+pub(crate) fn is_ignore_file(remote_path: &str) -> bool {
+    remote_path
+        .rsplit('/')
+        .next()
+        .map_or(false, |name| name.to_lowercase() == IGNORE_FILE)
+}
+
 pub(crate) async fn visit_all_dirs(
     initial: &str,
-    n_remote_path: usize,
+    config: &Config,
     ignore_filter: &Ignore,
     token: &str,
 ) -> Result<(Vec<String>, Vec<FileInfo>), Box<dyn Error>> {
+    let mut ignore_filter = ignore_filter.clone();
     let mut dirs = vec![initial.to_owned()];
     let mut index = 0;
     let mut files = vec![];
@@ -177,17 +373,33 @@ pub(crate) async fn visit_all_dirs(
         let new_index = dirs.len();
         for i in index..new_index {
             let dir = &dirs[i];
-            if ignore_filter.is_ignored(&dir[n_remote_path..]) {
+            let repo_dir = config.repo_path(dir);
+            if ignore_filter.is_ignored(&repo_dir, true) {
                 continue;
             }
 
             let entries = read_dir(dir, token).await?;
 
+            // The ignore file applies to its siblings, so it is read first.
+            // The one at the root is read by the caller beforehand.
+            for entry in entries.iter() {
+                if let Entry::File(FileInfo {
+                    path_display: Some(name),
+                    ..
+                }) = entry
+                {
+                    if is_ignore_file(name) && !repo_dir.is_root() {
+                        let (_, data) = files::download::download(name, token).await?;
+                        ignore_filter.add_rules(&repo_dir, name, data.to_vec())?;
+                    }
+                }
+            }
+
             for entry in entries.into_iter() {
                 match entry {
                     Entry::File(file_info) => {
                         if let Some(ref name) = file_info.path_display {
-                            if !ignore_filter.is_ignored(&name[n_remote_path..]) {
+                            if !ignore_filter.is_ignored(&config.repo_path(name), false) {
                                 files.push(file_info);
                             }
                         }
@@ -207,6 +419,171 @@ pub(crate) async fn visit_all_dirs(
     Ok((dirs, files))
 }
 
+/// Adds the rules of the remote ignore files in the directories between the repo root and `dir`,
+/// which are not read by `visit_all_dirs` starting at `dir`, like `with_parent_ignores`.
+/// The directories in `loaded` are skipped. Returns `false` if any of them is ignored.
+async fn add_remote_parent_ignores(
+    ignore_filter: &mut Ignore,
+    dir: &str,
+    config: &Config,
+    loaded: &mut Vec<RepoPath>,
+    token: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let mut parents: Vec<_> = config.repo_path(dir).ancestors().collect();
+    parents.reverse();
+    for parent in parents.into_iter() {
+        if ignore_filter.is_ignored(&parent, true) {
+            return Ok(false);
+        }
+        if loaded.contains(&parent) {
+            continue;
+        }
+
+        let path = config.remote_path_of(parent.join(IGNORE_FILE).as_str());
+        match files::download::download(&path, token).await {
+            Ok((_, data)) => ignore_filter.add_rules(&parent, &path, data.to_vec())?,
+            Err(e) if is_api_error(&*e, "path/not_found") => (),
+            Err(e) => return Err(e),
+        }
+        loaded.push(parent);
+    }
+    Ok(true)
+}
+
+/// Lists the remote directories and files of the repo like `visit_all_dirs`,
+/// but only within the sparse subtrees if any.
+/// The directories containing the subtrees are listed as well,
+/// and their ignore files are applied to the subtrees.
+pub(crate) async fn visit_repo_dirs(
+    config: &Config,
+    ignore_filter: &Ignore,
+    token: &str,
+) -> Result<(Vec<String>, Vec<FileInfo>), Box<dyn Error>> {
+    if config.sparse.is_none() {
+        return visit_all_dirs(&config.remote_path, config, ignore_filter, token).await;
+    }
+
+    let mut dirs = vec![config.remote_path.clone()];
+    let mut files = vec![];
+    let n_root = config.remote_path.trim_end_matches('/').len();
+    let mut ignore_filter = ignore_filter.clone();
+    let mut loaded = vec![];
+
+    for root in config.sync_roots().into_iter() {
+        if !add_remote_parent_ignores(&mut ignore_filter, &root, config, &mut loaded, token).await?
+        {
+            println!("Directory {} is ignored. Ignoring...", root);
+            continue;
+        }
+        for (i, _) in root.match_indices('/') {
+            if i > n_root && !dirs.iter().any(|d| d == &root[..i]) {
+                dirs.push(root[..i].to_owned());
+            }
+        }
+
+        match visit_all_dirs(&root, config, &ignore_filter, token).await {
+            Ok((sub_dirs, sub_files)) => {
+                for dir in sub_dirs.into_iter() {
+                    if !dirs.contains(&dir) {
+                        dirs.push(dir);
+                    }
+                }
+                files.extend(sub_files.into_iter());
+            }
+            Err(e) if is_api_error(&*e, "path/not_found") => {
+                println!("Directory {} does not exist. Ignoring...", root);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((dirs, files))
+}
+
+/// Adds the rules of `--ignore-file` and `--exclude`, which take precedence over the ignore files.
+pub(crate) async fn add_ignore_overrides(
+    ignore_filter: &mut Ignore,
+    ignore_file: Option<&str>,
+    excludes: &[String],
+) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = ignore_file {
+        let data = fs::read(path)
+            .await
+            .map_err(|e| format!("Cannot read ignore file {}: {}", path, e))?;
+        ignore_filter.add_overrides(path, data)?;
+    }
+    if !excludes.is_empty() {
+        ignore_filter.add_overrides("<command line>", excludes.join("\n").into_bytes())?;
+    }
+    Ok(())
+}
+
+/// Adds the rules of the local ignore files in the parent directories of `path`
+/// except for the root.
+pub(crate) async fn add_parent_ignores(
+    ignore_filter: &mut Ignore,
+    path: &RepoPath,
+    local_root: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut parents: Vec<_> = path.ancestors().collect();
+    parents.reverse();
+    for parent in parents.iter() {
+        let mut ignore_file = local_root.join(parent.as_str());
+        ignore_file.push(IGNORE_FILE);
+        ignore_filter.add_ignore_file(parent, ignore_file).await?;
+    }
+    Ok(())
+}
+
+/// Lists all the local files under `target` which are not ignored,
+/// together with their remote paths.
+pub(crate) async fn visit_local_files(
+    target: &Path,
+    config: &Config,
+    local_root: &Path,
+    ignore_filter: &Ignore,
+) -> Result<Vec<(PathBuf, String)>, Box<dyn Error>> {
+    let mut ignore_filter = ignore_filter.clone();
+    let mut dirs = vec![target.to_owned()];
+    let mut files = vec![];
+
+    let target_dir = config.repo_path(&construct_remote_path(target, config, local_root)?);
+    add_parent_ignores(&mut ignore_filter, &target_dir, local_root).await?;
+
+    while dirs.len() != 0 {
+        let mut new_dirs = vec![];
+        for dir in dirs.iter() {
+            let repo_dir = config.repo_path(&construct_remote_path(dir, config, local_root)?);
+            if !repo_dir.is_root() {
+                ignore_filter
+                    .add_ignore_file(&repo_dir, dir.join(IGNORE_FILE))
+                    .await?;
+            }
+
+            let mut reads = fs::read_dir(dir).await?;
+            while let Some(entry) = reads.next().await {
+                let path = entry?.path();
+                if !path.is_file() && !path.is_dir() {
+                    continue;
+                }
+                let remote_path = construct_remote_path(&path, config, local_root)?;
+                if ignore_filter.is_ignored(&config.repo_path(&remote_path), path.is_dir()) {
+                    continue;
+                }
+                if path.is_file() {
+                    files.push((path, remote_path));
+                } else if path.is_dir() {
+                    new_dirs.push(path);
+                }
+            }
+        }
+
+        dirs = new_dirs;
+    }
+
+    Ok(files)
+}
+
 pub(crate) async fn download_file(
     remote_path: &str,
     config: &Config,
@@ -220,7 +597,7 @@ pub(crate) async fn download_file(
         remote_path,
         local_file.to_string_lossy()
     );
-    let (info, data) = download::download(remote_path, token).await?;
+    let (info, data) = remote::download_remote(remote_path, local_root, conn, token).await?;
     upsert_metadata(local_root, conn, config, info, &data).await?;
     fs::write(local_file, data).await?;
     Ok(())
@@ -238,6 +615,37 @@ pub(crate) async fn create_dirs(
     Ok(())
 }
 
+/// Fails if any file is left conflicted by pull.
+pub(crate) fn ensure_no_conflicts(conn: &rusqlite::Connection) -> Result<(), Box<dyn Error>> {
+    let conflicts = db::list_conflicts(conn)?;
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    let paths: Vec<_> = conflicts.iter().map(|c| &c.path[..]).collect();
+    Err(format!(
+        "Unresolved conflicts in {}. Use resolve command after fixing them.",
+        paths.join(", ")
+    )
+    .into())
+}
+
+/// Asks a yes/no question on the terminal. Anything but yes is regarded as no.
+pub(crate) async fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    use tokio::io;
+    use tokio::prelude::*;
+
+    let mut stdout = io::stdout();
+    stdout
+        .write_all(format!("{} [y/N]: ", question).as_bytes())
+        .await?;
+    stdout.flush().await?;
+    let mut buf = String::new();
+    io::BufReader::new(io::stdin()).read_line(&mut buf).await?;
+    let answer = buf.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub(crate) enum FileStatus {
     NotChanged,
@@ -299,6 +707,36 @@ pub(crate) fn file_status(
 mod tests {
     use super::*;
 
+    #[test]
+    fn sparse_test() {
+        let mut config = Config::new("/hoge");
+        assert_eq!(config.is_selected("/hoge/fuga/piyo.rs"), true);
+
+        config.sparse = Some(vec!["fuga/piyo".to_owned(), "foo".to_owned()]);
+        assert_eq!(config.sync_roots(), vec!["/hoge/fuga/piyo", "/hoge/foo"]);
+        assert_eq!(config.is_selected("/hoge/fuga/piyo/bar.rs"), true);
+        assert_eq!(config.is_selected("/hoge/foo/bar.rs"), true);
+        assert_eq!(config.is_selected("/hoge/fuga/bar.rs"), false);
+        assert_eq!(config.is_selected("/hoge/bar.rs"), false);
+        assert_eq!(config.is_sparse_ancestor("/hoge/fuga"), true);
+        assert_eq!(config.is_sparse_ancestor("/hoge"), true);
+        assert_eq!(config.is_sparse_ancestor("/hoge/bar"), false);
+        assert_eq!(config.is_selected("/Hoge/Fuga/Piyo/bar.rs"), true);
+        assert_eq!(config.relative_path("/HOGE/Foo/bar.rs"), "Foo/bar.rs");
+    }
+
+    #[test]
+    fn is_in_dir_test() {
+        assert_eq!(is_in_dir("/hoge/fuga", "/hoge"), true);
+        assert_eq!(is_in_dir("/hoge/fuga", "/hoge/"), true);
+        assert_eq!(is_in_dir("/hoge", "/hoge"), true);
+        assert_eq!(is_in_dir("/hogefuga", "/hoge"), false);
+        assert_eq!(is_in_dir("/hoge", "/hoge/fuga"), false);
+        assert_eq!(is_in_dir("/hoge/fuga", ""), true);
+        assert_eq!(is_in_dir("/Hoge/fuga", "/hoGE"), true);
+        assert_eq!(is_in_dir("/ほげ/fuga", "/hoge"), false);
+    }
+
     #[test]
     fn file_status_test() {
         assert_eq!(
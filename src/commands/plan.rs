@@ -0,0 +1,538 @@
+use super::merge::{merge_file, Strategy};
+use super::{
+    construct_local_path, construct_meta_path, create_dirs, create_metadir_for_file, download_file,
+    ensure_no_conflicts, is_in_dir, Config,
+};
+use crate::files::get_metadata::get_metadata;
+use crate::files::{copy, delete, is_api_error, upload, FileInfo};
+use crate::{bytes_to_hex_string, content_hash, db, file_hash};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+/// A single step of synchronization. All the paths are remote paths.
+/// `local_hash` is the hash of the working file when the plan is made, or `None` if absent.
+/// It is checked together with `rev` by `Plan::verify` before a saved plan is applied.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum Action {
+    /// Create a local directory.
+    CreateDir { path: String },
+    /// Remove a local directory if it is empty.
+    RemoveDir { path: String },
+    /// Rename a local directory to `to`, which differs from `from` only in case.
+    RenameDir { from: String, to: String },
+    /// Rename the working file to `to`, which differs from `from` only in case.
+    Rename { from: String, to: String },
+    /// Download the remote file of `rev` into the working tree.
+    Download {
+        path: String,
+        #[serde(default)]
+        rev: Option<String>,
+        #[serde(default)]
+        local_hash: Option<String>,
+    },
+    /// Record the remote file without downloading it.
+    Placeholder {
+        path: String,
+        rev: String,
+        size: u64,
+        hash: String,
+    },
+    /// Apply the remote changes to the locally modified file.
+    Merge {
+        path: String,
+        #[serde(default)]
+        strategy: Strategy,
+    },
+    /// Both sides have the same content. Only the `.dsync` copy is updated.
+    MarkSynced { path: String },
+    /// Remove the local file.
+    Delete {
+        path: String,
+        #[serde(default)]
+        local_hash: Option<String>,
+    },
+    /// Stop tracking the file but keep the working file.
+    Untrack {
+        path: String,
+        #[serde(default)]
+        local_hash: Option<String>,
+    },
+    /// Upload the staged file. `rev` is the remote revision to be overwritten.
+    Upload { path: String, rev: Option<String> },
+    /// Remove the remote file.
+    DeleteRemote { path: String },
+    /// Record that Dropbox made the conflicted copy `path` of `original`.
+    LinkCopy { path: String, original: String },
+    /// Nothing is done for the file.
+    Skip { path: String, reason: String },
+    /// Nothing is done for the file, but the user must look at it.
+    /// `kind` is recorded as the kind of `db::Conflict`.
+    Conflict {
+        path: String,
+        reason: String,
+        #[serde(default = "removed_kind")]
+        kind: u8,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::CreateDir { path } => write!(f, "create dir    {}", path),
+            Action::RemoveDir { path } => write!(f, "remove dir    {}", path),
+            Action::RenameDir { from, to } => write!(f, "rename dir    {} -> {}", from, to),
+            Action::Rename { from, to } => write!(f, "rename        {} -> {}", from, to),
+            Action::Download { path, .. } => write!(f, "download      {}", path),
+            Action::Placeholder { path, .. } => write!(f, "placeholder   {}", path),
+            Action::Merge { path, strategy } => {
+                write!(f, "merge         {} ({})", path, strategy)
+            }
+            Action::MarkSynced { path } => write!(f, "mark synced   {}", path),
+            Action::Delete { path, .. } => write!(f, "delete        {}", path),
+            Action::Untrack { path, .. } => write!(f, "untrack       {}", path),
+            Action::Upload { path, .. } => write!(f, "upload        {}", path),
+            Action::DeleteRemote { path } => write!(f, "delete remote {}", path),
+            Action::LinkCopy { path, original } => {
+                write!(f, "link copy     {} -> {}", path, original)
+            }
+            Action::Skip { path, reason } => write!(f, "skip          {} ({})", path, reason),
+            Action::Conflict { path, reason, .. } => {
+                write!(f, "conflict      {} ({})", path, reason)
+            }
+        }
+    }
+}
+
+fn removed_kind() -> u8 {
+    db::Conflict::REMOVED
+}
+
+impl Action {
+    /// Makes a placeholder action for the remote file if possible.
+    pub(crate) fn placeholder(info: &FileInfo) -> Option<Action> {
+        Some(Action::Placeholder {
+            path: info.path_display.clone()?,
+            rev: info.rev.clone(),
+            size: info.size,
+            hash: info.content_hash.clone()?,
+        })
+    }
+}
+
+/// The list of actions to synchronize the repo for `remote_path`.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Plan {
+    pub remote_path: String,
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    pub(crate) fn new(remote_path: &str) -> Self {
+        Plan {
+            remote_path: remote_path.to_owned(),
+            actions: vec![],
+        }
+    }
+
+    pub(crate) fn push(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+
+    pub(crate) fn extend(&mut self, actions: impl IntoIterator<Item = Action>) {
+        self.actions.extend(actions);
+    }
+
+    pub(crate) async fn load(path: impl AsRef<Path>) -> Result<Plan, Box<dyn Error>> {
+        let data = fs::read(path).await?;
+        Ok(serde_json::from_str(std::str::from_utf8(&data)?)?)
+    }
+
+    /// Prints or saves the plan as requested.
+    /// Returns `false` if the plan must not be applied. A saved plan is applied later by `apply`.
+    pub(crate) async fn review(
+        &self,
+        dry_run: bool,
+        plan_out: Option<&str>,
+    ) -> Result<bool, Box<dyn Error>> {
+        if let Some(plan_out) = plan_out {
+            fs::write(plan_out, serde_json::to_string_pretty(self)?).await?;
+            println!(
+                "The plan is saved at {}. Apply it by apply command after reviewing.",
+                plan_out
+            );
+        }
+
+        if dry_run {
+            if self.actions.is_empty() {
+                println!("Nothing to do.");
+            }
+            for action in self.actions.iter() {
+                println!("  {}", action);
+            }
+        }
+
+        Ok(!dry_run && plan_out.is_none())
+    }
+
+    /// Checks that the files are still as they were when the plan was made,
+    /// so that a saved plan does not overwrite or remove the changes made since then.
+    pub(crate) async fn verify(
+        &self,
+        config: &Config,
+        local_root: &Path,
+        token: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        // The paths of the actions are the ones after the renames.
+        let mut renames = vec![];
+        let mut outdated = vec![];
+        for action in self.actions.iter() {
+            let (path, local_hash) = match action {
+                Action::RenameDir { from, to } | Action::Rename { from, to } => {
+                    renames.push((to.clone(), from.clone()));
+                    continue;
+                }
+                Action::Download {
+                    path, local_hash, ..
+                }
+                | Action::Delete { path, local_hash }
+                | Action::Untrack { path, local_hash } => (path, local_hash),
+                _ => continue,
+            };
+
+            let mut curr_path = path.clone();
+            for (to, from) in renames.iter().rev() {
+                if is_in_dir(&curr_path, to) {
+                    curr_path = format!("{}{}", from, &curr_path[to.len()..]);
+                }
+            }
+            if &working_hash(&curr_path, config, local_root).await != local_hash {
+                outdated.push(path.clone());
+                continue;
+            }
+
+            let remote_changed = match action {
+                Action::Download { rev, .. } => match get_metadata(path, token).await {
+                    Ok(info) => Some(info.rev) != *rev,
+                    Err(e) if is_api_error(&*e, "path/not_found") => true,
+                    Err(e) => return Err(e),
+                },
+                Action::Delete { .. } => match get_metadata(path, token).await {
+                    Ok(_) => true,
+                    Err(e) if is_api_error(&*e, "path/not_found") => false,
+                    Err(e) => return Err(e),
+                },
+                _ => false,
+            };
+            if remote_changed {
+                outdated.push(path.clone());
+            }
+        }
+
+        if outdated.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Files {} are changed since the plan was made. Make the plan again.",
+                outdated.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+/// The hash of the working file of `path` as recorded in the actions.
+pub(crate) async fn working_hash(path: &str, config: &Config, local_root: &Path) -> Option<String> {
+    file_hash(construct_local_path(path, config, local_root))
+        .await
+        .ok()
+        .map(|hash| bytes_to_hex_string(&hash))
+}
+
+/// Renames the working file or directory and its `.dsync` copy if they exist.
+async fn rename_local(
+    from: &str,
+    to: &str,
+    config: &Config,
+    local_root: &Path,
+) -> Result<(), Box<dyn Error>> {
+    for (from, to) in [
+        (
+            construct_local_path(from, config, local_root),
+            construct_local_path(to, config, local_root),
+        ),
+        (
+            construct_meta_path(from, config, local_root),
+            construct_meta_path(to, config, local_root),
+        ),
+    ]
+    .iter()
+    {
+        if from.exists() {
+            fs::rename(from, to).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies `plan` to the repo. `config.sync_dirs` and `config.sparse` are updated
+/// along with directory actions.
+pub(crate) async fn execute(
+    plan: &Plan,
+    config: &mut Config,
+    local_root: &Path,
+    conn: &Connection,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    if plan.remote_path != config.remote_path {
+        return Err(format!(
+            "The plan is for {}, not for {}.",
+            plan.remote_path, config.remote_path
+        )
+        .into());
+    }
+
+    let uploading = plan.actions.iter().any(|action| match action {
+        Action::Upload { .. } | Action::DeleteRemote { .. } => true,
+        _ => false,
+    });
+    if uploading {
+        ensure_no_conflicts(conn)?;
+    }
+
+    for action in plan.actions.iter() {
+        match action {
+            Action::CreateDir { path } => {
+                create_dirs(path, config, local_root).await?;
+                if !config.sync_dirs.contains(path) {
+                    config.sync_dirs.push(path.clone());
+                }
+            }
+            Action::RemoveDir { path } => {
+                let local_path = construct_local_path(path, config, local_root);
+                let meta_path = construct_meta_path(path, config, local_root);
+                if fs::remove_dir(&meta_path).await.is_err()
+                    || fs::remove_dir(&local_path).await.is_err()
+                {
+                    println!(
+                        "Directory {} is not empty. Ignoring...",
+                        local_path.display()
+                    );
+                } else {
+                    println!("Removing empty directory {}.", local_path.display());
+                }
+                config.sync_dirs.retain(|d| d != path);
+            }
+            Action::RenameDir { from, to } => {
+                println!("Renaming directory {} to {}.", from, to);
+                rename_local(from, to, config, local_root).await?;
+                for dir in config.sync_dirs.iter_mut() {
+                    if is_in_dir(dir, from) {
+                        *dir = format!("{}{}", to, &dir[from.len()..]);
+                    }
+                }
+                if let Some(sparse) = config.sparse.clone() {
+                    let sparse = sparse
+                        .into_iter()
+                        .map(|dir| {
+                            let dir = config.remote_path_of(&dir);
+                            let dir = if is_in_dir(&dir, from) {
+                                format!("{}{}", to, &dir[from.len()..])
+                            } else {
+                                dir
+                            };
+                            config.relative_path(&dir).to_owned()
+                        })
+                        .collect();
+                    config.sparse = Some(sparse);
+                }
+                db::rename_dir_entries(conn, from, to)?;
+            }
+            Action::Rename { from, to } => {
+                println!("Renaming file {} to {}.", from, to);
+                rename_local(from, to, config, local_root).await?;
+                db::rename_file_entry(conn, from, to)?;
+            }
+            Action::Download { path, .. } => {
+                download_file(path, config, local_root, conn, token).await?;
+            }
+            Action::Placeholder {
+                path,
+                rev,
+                size,
+                hash,
+            } => {
+                println!("Recording file {} ...", path);
+                db::upsert_file(conn, &db::FileData::new(path.clone(), hex::decode(hash)?))?;
+                db::upsert_placeholder(
+                    conn,
+                    &db::Placeholder::new(path.clone(), Some(rev.clone()), *size as i64),
+                )?;
+            }
+            Action::Merge { path, strategy } => {
+                merge_file(path, *strategy, config, local_root, conn, token).await?;
+            }
+            Action::MarkSynced { path } => {
+                let local_path = construct_local_path(path, config, local_root);
+                let meta_path = construct_meta_path(path, config, local_root);
+                println!(
+                    "Both remote and local repo adopt the same change for file {}.",
+                    local_path.display()
+                );
+                create_metadir_for_file(path, config, local_root).await?;
+                fs::copy(local_path, meta_path).await?;
+            }
+            Action::Delete { path, .. } => {
+                let local_path = construct_local_path(path, config, local_root);
+                let meta_path = construct_meta_path(path, config, local_root);
+                println!("Removing file {}.", local_path.display());
+                fs::remove_file(local_path).await?;
+                fs::remove_file(meta_path).await?;
+                db::delete_file_entry(conn, path)?;
+            }
+            Action::Untrack { path, .. } => {
+                let meta_path = construct_meta_path(path, config, local_root);
+                println!("Untracking file {}.", path);
+                fs::remove_file(meta_path).await.ok();
+                db::delete_file_entry(conn, path)?;
+            }
+            Action::Upload { path, rev } => {
+                let local_path = construct_local_path(path, config, local_root);
+                let meta_path = construct_meta_path(path, config, local_root);
+                println!("Uploading {} ...", local_path.display());
+                let data = fs::read(&local_path).await?;
+                let info = upload::upload(data.clone(), path, rev.as_deref(), token).await?;
+                // Dropbox keeps the case of the existing name, which is renamed explicitly.
+                if path.rsplit('/').next() != Some(&info.name[..]) {
+                    let from = info.path_display.as_deref().unwrap_or(path);
+                    println!("Renaming remote file {} to {} ...", from, path);
+                    copy::move_file(from, path, token).await?;
+                }
+                create_metadir_for_file(path, config, local_root).await?;
+                fs::write(&meta_path, &data).await?;
+                db::upsert_file(
+                    conn,
+                    &db::FileData::new(
+                        path.clone(),
+                        info.content_hash
+                            .and_then(|s| hex::decode(s).ok())
+                            .unwrap_or_else(|| content_hash(&data).to_vec()),
+                    ),
+                )?;
+                db::delete_update(conn, path)?;
+            }
+            Action::DeleteRemote { path } => {
+                let meta_path = construct_meta_path(path, config, local_root);
+                println!("Removing remote file {} ...", path);
+                delete::delete(path, token).await?;
+                fs::remove_file(meta_path).await.ok();
+                db::delete_file_entry(conn, path)?;
+            }
+            Action::LinkCopy { path, original } => {
+                println!("File {} is a conflicted copy of {}.", path, original);
+                db::upsert_conflicted_copy(conn, path, original)?;
+                if config.fold_conflicted_copies {
+                    let copy_path = construct_local_path(path, config, local_root);
+                    db::upsert_conflict(
+                        conn,
+                        &db::Conflict::new(
+                            original.clone(),
+                            db::Conflict::REMOTE_COPY,
+                            Some(copy_path.to_string_lossy().into_owned()),
+                        ),
+                    )?;
+                }
+            }
+            Action::Skip { path, reason } => {
+                println!("File {} {}. Ignoring...", path, reason);
+            }
+            Action::Conflict { path, reason, kind } => {
+                println!("CONFLICT: File {} {}.", path, reason);
+                db::upsert_conflict(conn, &db::Conflict::new(path.clone(), *kind, None))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_json_test() {
+        let mut plan = Plan::new("/hoge");
+        plan.push(Action::CreateDir {
+            path: "/hoge/fuga".to_owned(),
+        });
+        plan.push(Action::Upload {
+            path: "/hoge/fuga/piyo.txt".to_owned(),
+            rev: None,
+        });
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(
+            json,
+            r#"{"remote_path":"/hoge","actions":[{"action":"create_dir","path":"/hoge/fuga"},{"action":"upload","path":"/hoge/fuga/piyo.txt","rev":null}]}"#
+        );
+        assert_eq!(serde_json::from_str::<Plan>(&json).unwrap(), plan);
+
+        let action = r#"{"action":"conflict","path":"/hoge/a","reason":"r"}"#;
+        assert_eq!(
+            serde_json::from_str::<Action>(action).unwrap(),
+            Action::Conflict {
+                path: "/hoge/a".to_owned(),
+                reason: "r".to_owned(),
+                kind: db::Conflict::REMOVED,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn review_test() {
+        let plan = Plan::new("/hoge");
+        let plan_out = "dsync_plan_review_test.json";
+        assert_eq!(plan.review(false, None).await.unwrap(), true);
+        assert_eq!(plan.review(true, None).await.unwrap(), false);
+        assert_eq!(plan.review(false, Some(plan_out)).await.unwrap(), false);
+        assert_eq!(Plan::load(plan_out).await.unwrap(), plan);
+        fs::remove_file(plan_out).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn conflict_untrack_test() {
+        let root = Path::new("dsync_plan_conflict_test");
+        fs::create_dir_all(root).await.unwrap();
+        let mut config = Config::new("/hoge");
+        let (conn, _) = db::connect(root).unwrap();
+        let path = "/hoge/a.txt".to_owned();
+        db::upsert_file(&conn, &db::FileData::new(path.clone(), vec![0])).unwrap();
+
+        let mut plan = Plan::new("/hoge");
+        plan.push(Action::Conflict {
+            path: path.clone(),
+            reason: "is remotely removed but locally modified".to_owned(),
+            kind: db::Conflict::REMOVED,
+        });
+        plan.push(Action::Untrack {
+            path: path.clone(),
+            local_hash: None,
+        });
+        execute(&plan, &mut config, root, &conn, "").await.unwrap();
+        let conflicts: Vec<_> = db::list_conflicts(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.path)
+            .collect();
+        assert_eq!(conflicts, vec![path]);
+
+        drop(conn);
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -1,123 +1,251 @@
+use super::conflicted::plan_link_copies;
+use super::ignore_changes::detect_ignore_changes;
+use super::merge::{strategy_for, Strategy};
+use super::plan::{execute, working_hash, Action, Plan};
+use super::remote::{load_listing, refresh, with_dir_ignores};
 use super::{
-    construct_local_path, construct_meta_path, create_dirs, download_file, file_status,
-    load_config, save_config, visit_all_dirs, Config, FileStatus,
+    add_ignore_overrides, confirm, connect_db, construct_local_path, construct_meta_path,
+    file_status, is_in_dir, load_config, save_config, Config, FileStatus,
 };
-use crate::files::{download, FileInfo};
-use crate::ignore::parce_ignore;
-use crate::{content_hash, db, file_hash};
-use diffmerge::merge;
-use rusqlite::Connection;
+use crate::files::FileInfo;
+use crate::ignore::parce_repo_ignore;
+use crate::{bytes_to_hex_string, db, file_hash};
 use std::collections::HashMap;
 
 use std::path::Path;
 
 pub async fn pull(
     local_root: impl AsRef<Path>,
+    no_fetch: bool,
+    strategy: Option<&str>,
+    ignore_file: Option<&str>,
+    excludes: &[String],
+    dry_run: bool,
+    plan_out: Option<&str>,
     token: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let ignore_filter = parce_ignore().await?;
+    let strategy = strategy.map(|s| s.parse::<Strategy>()).transpose()?;
     let local_root = local_root.as_ref();
-    let config = load_config(local_root).await.map_err(|_| {
+    let mut config = load_config(local_root).await.map_err(|_| {
         format!(
             "Directory {} is not a correct dsync repo.",
             local_root.display()
         )
     })?;
-    let conn = db::connect(local_root)?;
-
-    println!("Checking updates in {}", &config.remote_path);
-    let (remote_dirs, remote_files) = {
-        let (mut dirs, files) = visit_all_dirs(
-            &config.remote_path,
-            config.remote_path.len(),
-            &ignore_filter,
-            token,
-        )
-        .await?;
-        dirs.sort_unstable_by(|a, b| b.cmp(a)); // desc sorted
-        (dirs, files)
-    };
-    let local_dirs = {
-        let mut dirs: Vec<&str> = config.sync_dirs.iter().map(|s| &**s).collect();
-        dirs.sort_unstable_by(|a, b| b.cmp(a));
-        dirs
-    };
-    let local_files = db::list_files(&conn)?;
-
-    let mut i_remote_dir = 0;
-    let mut i_local_dir = 0;
-    let mut dir_to_remove = vec![];
-
-    while i_remote_dir < remote_dirs.len() && i_local_dir < local_dirs.len() {
-        if i_local_dir == local_dirs.len() {
-            for remote_dir in remote_dirs[i_remote_dir..].into_iter() {
-                create_dirs(remote_dir, &config, local_root).await?;
-            }
-        }
+    let mut ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+    add_ignore_overrides(&mut ignore_filter, ignore_file, excludes).await?;
+    let mut conn = connect_db(&config, local_root)?;
 
-        if i_remote_dir == remote_dirs.len() {
-            dir_to_remove.extend(local_dirs[i_local_dir..].into_iter());
+    if no_fetch {
+        if !db::has_cursor(&conn)? {
+            return Err("Nothing has been fetched yet. Run fetch command first.".into());
         }
+    } else {
+        println!("Checking updates in {}", &config.remote_path);
+        refresh(&config, &mut conn, token).await?;
+    }
+    let (remote_dirs, mut remote_files) = load_listing(&config, &conn, local_root, &ignore_filter)?;
+    let ignore_filter = with_dir_ignores(&ignore_filter, &config, &conn, local_root)?;
+    // The tracked files ignored by the rules are neither updated nor removed.
+    let local_files = db::list_files_by_lower(&conn)?
+        .into_iter()
+        .filter(|(_, file)| config.is_selected(&file.path))
+        .filter(|(_, file)| !ignore_filter.is_ignored(&config.repo_path(&file.path), false))
+        .collect();
 
-        if remote_dirs[i_remote_dir] == local_dirs[i_local_dir] {
-            i_remote_dir += 1;
-            i_local_dir += 1;
-        } else if &remote_dirs[i_remote_dir][..] > local_dirs[i_local_dir] {
-            create_dirs(&remote_dirs[i_remote_dir], &config, local_root).await?;
-            i_remote_dir += 1;
-        } else if &remote_dirs[i_remote_dir][..] < local_dirs[i_local_dir] {
-            dir_to_remove.push(local_dirs[i_local_dir]);
-            i_local_dir += 1;
+    let changes = detect_ignore_changes(&ignore_filter, &config, &conn, &remote_files)?;
+    let mut to_untrack = vec![];
+    let mut accepted = true;
+    if let Some(ref changes) = changes {
+        changes.report(&config);
+        if !changes.ignored.is_empty() {
+            if dry_run
+                || confirm("Untrack the newly ignored files keeping the local copies?").await?
+            {
+                to_untrack = changes.ignored.clone();
+            } else {
+                accepted = false;
+            }
+        }
+        if !changes.included.is_empty()
+            && !dry_run
+            && !confirm("Download the newly included files?").await?
+        {
+            accepted = false;
+            remote_files.retain(|info| {
+                info.path_display
+                    .as_ref()
+                    .map_or(true, |path| !changes.included.contains(path))
+            });
         }
     }
 
-    if i_local_dir < local_dirs.len() {
-        dir_to_remove.extend(local_dirs[i_local_dir..].iter());
-    }
+    let placeholders = db::list_placeholders(&conn)?;
+    let linked = db::list_conflicted_copies(&conn)?;
 
-    let files_to_unlink = update_files(
+    let mut plan = Plan::new(&config.remote_path);
+    let renames = plan_rename_dirs(&mut plan, &remote_dirs, local_root, &config);
+    plan_create_dirs(&mut plan, &remote_dirs, &config);
+    let files_to_unlink = plan_update_files(
+        &mut plan,
         &remote_files,
         local_files,
+        &placeholders,
+        &renames,
+        strategy,
         local_root,
         &config,
-        &conn,
-        token,
     )
     .await?;
+    plan_unlink_files(
+        &mut plan,
+        &files_to_unlink,
+        &placeholders,
+        &renames,
+        local_root,
+        &config,
+    )
+    .await?;
+    for path in to_untrack.into_iter() {
+        let local_hash = working_hash(&path, &config, local_root).await;
+        plan.push(Action::Untrack { path, local_hash });
+    }
+    plan_link_copies(&mut plan, &remote_files, &linked);
+    plan_remove_dirs(&mut plan, &remote_dirs, &config);
 
-    unlink_files(&files_to_unlink, local_root, &config, &conn).await?;
-
-    // remove directories
-    dir_to_remove.sort_unstable_by(|a, b| b.cmp(a));
-    for dir in dir_to_remove.iter() {
-        let local_path = construct_local_path(dir, &config, local_root);
-        let meta_path = construct_meta_path(dir, &config, local_root);
-        if tokio::fs::remove_dir(&meta_path).await.is_err()
-            || tokio::fs::remove_dir(&local_path).await.is_err()
-        {
-            println!(
-                "Directory {} is not empty. Ignoring...",
-                local_path.display()
-            );
-        } else {
-            println!("Removing empty directory {}.", local_path.display());
-        }
+    if !plan.review(dry_run, plan_out).await? {
+        return Ok(());
     }
 
-    let mut config = config.clone();
-    config.sync_dirs = remote_dirs;
+    execute(&plan, &mut config, local_root, &conn, token).await?;
     save_config(&config, local_root).await?;
+    if let Some(ref changes) = changes {
+        if accepted {
+            changes.save(&conn)?;
+        }
+    }
 
     Ok(())
 }
 
-async fn update_files(
+/// Splits the path into the parent directory and the name.
+fn split_name(path: &str) -> (&str, &str) {
+    let i = path.rfind('/').unwrap_or(0);
+    (&path[..i], path[i..].trim_start_matches('/'))
+}
+
+/// Applies the renames of the directories planned by `plan_rename_dirs` to the path.
+fn renamed_path(path: &str, renames: &[(String, String)]) -> String {
+    let mut path = path.to_owned();
+    for (from, to) in renames.iter() {
+        if is_in_dir(&path, from) {
+            path = format!("{}{}", to, &path[from.len()..]);
+        }
+    }
+    path
+}
+
+/// Checks if the local directory of `path` has an entry named exactly `name`,
+/// which differs from the name of `path` only in case.
+/// On a case-insensitive file system, `path` itself is not found by `name`.
+fn collides(path: &str, name: &str, local_root: &Path, config: &Config) -> bool {
+    let (dir, _) = split_name(path);
+    std::fs::read_dir(construct_local_path(dir, config, local_root))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .any(|e| e.file_name().to_string_lossy() == name)
+        })
+        .unwrap_or(false)
+}
+
+/// Plans the local renames of the directories renamed remotely only in case, parents first.
+/// Returns the renames as `(from, to)`, which are applied to the other actions.
+fn plan_rename_dirs(
+    plan: &mut Plan,
+    remote_dirs: &[String],
+    local_root: &Path,
+    config: &Config,
+) -> Vec<(String, String)> {
+    let mut dirs: Vec<_> = remote_dirs.iter().collect();
+    dirs.sort_unstable_by_key(|d| d.to_lowercase()); // parents first
+
+    let mut renames: Vec<(String, String)> = vec![];
+    for dir in dirs.into_iter() {
+        let lower = dir.to_lowercase();
+        let local_dir = match config.sync_dirs.iter().find(|d| d.to_lowercase() == lower) {
+            Some(local_dir) => local_dir,
+            None => continue,
+        };
+        let from = renamed_path(local_dir, &renames);
+        let (parent, from_name) = split_name(&from);
+        let (_, to_name) = split_name(dir);
+        if from_name == to_name {
+            continue;
+        }
+
+        let to = format!("{}/{}", parent, to_name);
+        if collides(local_dir, to_name, local_root, config) {
+            plan.push(Action::Conflict {
+                path: to,
+                reason: format!("differs only in case from the local directory {}", from),
+                kind: db::Conflict::CASE_COLLISION,
+            });
+            continue;
+        }
+        plan.push(Action::RenameDir {
+            from: from.clone(),
+            to: to.clone(),
+        });
+        renames.push((from, to));
+    }
+    renames
+}
+
+fn plan_create_dirs(plan: &mut Plan, remote_dirs: &[String], config: &Config) {
+    let mut dirs: Vec<_> = remote_dirs
+        .iter()
+        .filter(|d| {
+            !config
+                .sync_dirs
+                .iter()
+                .any(|s| s.to_lowercase() == d.to_lowercase())
+        })
+        .collect();
+    dirs.sort_unstable(); // parents first
+    for dir in dirs.into_iter() {
+        plan.push(Action::CreateDir { path: dir.clone() });
+    }
+}
+
+fn plan_remove_dirs(plan: &mut Plan, remote_dirs: &[String], config: &Config) {
+    let mut dirs: Vec<_> = config
+        .sync_dirs
+        .iter()
+        .filter(|d| {
+            !remote_dirs
+                .iter()
+                .any(|r| r.to_lowercase() == d.to_lowercase())
+        })
+        .filter(|d| config.is_selected(d) || config.is_sparse_ancestor(d))
+        .collect();
+    dirs.sort_unstable_by(|a, b| b.cmp(a)); // children first
+    for dir in dirs.into_iter() {
+        plan.push(Action::RemoveDir { path: dir.clone() });
+    }
+}
+
+/// Plans the updates of the files in `remote_files`.
+/// `local_files` are the tracked files by `path_lower`, and the ones left are returned.
+async fn plan_update_files(
+    plan: &mut Plan,
     remote_files: &[FileInfo],
     local_files: HashMap<String, db::FileData>,
+    placeholders: &HashMap<String, db::Placeholder>,
+    renames: &[(String, String)],
+    strategy: Option<Strategy>,
     local_root: &Path,
     config: &Config,
-    conn: &Connection,
-    token: &str,
 ) -> Result<HashMap<String, db::FileData>, Box<dyn std::error::Error>> {
     let mut local_files = local_files;
     for remote_file in remote_files.into_iter() {
@@ -131,13 +259,52 @@ async fn update_files(
         }
 
         let path = remote_file.path_display.as_ref().unwrap(); // ensured
+        let tracked = local_files.remove(&db::path_lower(path));
+        // The current path of the tracked file, which may differ from the remote one in case.
+        let curr_path = tracked.as_ref().map_or(path, |f| &f.path);
+
+        if let Some(ref file) = tracked {
+            let from = renamed_path(&file.path, renames);
+            let (parent, from_name) = split_name(&from);
+            let (_, to_name) = split_name(path);
+            if from_name != to_name {
+                let to = format!("{}/{}", parent, to_name);
+                if collides(&file.path, to_name, local_root, config) {
+                    plan.push(Action::Conflict {
+                        path: to,
+                        reason: format!("differs only in case from the local file {}", from),
+                        kind: db::Conflict::CASE_COLLISION,
+                    });
+                    continue;
+                }
+                plan.push(Action::Rename { from, to });
+            }
+        }
 
-        let local_path = construct_local_path(path, &config, &local_root);
-        let meta_path = construct_meta_path(path, &config, &local_root);
+        if placeholders.contains_key(curr_path) {
+            // Only the record is updated. Not downloaded until fetched.
+            let repo_hash = tracked.map(|f| f.hash);
+            if repo_hash != remote_hash {
+                plan.extend(Action::placeholder(remote_file));
+            }
+            continue;
+        }
+
+        let local_path = construct_local_path(curr_path, &config, &local_root);
+        let meta_path = construct_meta_path(curr_path, &config, &local_root);
 
         let curr_hash = file_hash(&local_path).await.ok();
         let orig_hash = file_hash(&meta_path).await.ok();
-        let repo_hash = local_files.remove(path).map(|f| f.hash);
+        // The actions are done after the renames.
+        let path = match tracked {
+            Some(ref file) => {
+                let renamed = renamed_path(&file.path, renames);
+                format!("{}/{}", split_name(&renamed).0, split_name(path).1)
+            }
+            None => path.clone(),
+        };
+        let repo_hash = tracked.map(|f| f.hash);
+        let local_hash = curr_hash.as_ref().map(|h| bytes_to_hex_string(h));
 
         match file_status(
             remote_hash.as_deref(),
@@ -145,100 +312,24 @@ async fn update_files(
             repo_hash.as_deref(),
             orig_hash.as_ref().map(|h| &h[..]),
         ) {
-            FileStatus::NotSaved => {
-                println!(
-                    "File {} is updated but being edited. Use add command to merge. Ignoring...",
-                    local_path.display()
-                );
-            }
-            FileStatus::ToBeUpdated => {
-                println!("Updating file {}...", local_path.display());
-                download_file(&path, &config, &local_root, &conn, &token).await?;
-            }
-            FileStatus::ToBeCreated => {
-                println!("Creating file {}...", local_path.display());
-                download_file(&path, &config, &local_root, &conn, &token).await?;
+            FileStatus::NotSaved => plan.push(Action::Skip {
+                path,
+                reason: "is updated but being edited. Use add command to merge".to_owned(),
+            }),
+            FileStatus::ToBeCreated if config.lazy => {
+                plan.extend(Action::placeholder(remote_file));
             }
+            FileStatus::ToBeUpdated | FileStatus::ToBeCreated => plan.push(Action::Download {
+                path,
+                rev: Some(remote_file.rev.clone()),
+                local_hash,
+            }),
             FileStatus::Conflicted => {
-                // merge
-                println!(
-                    "Conflict found in file {}. Trying to merge...",
-                    local_path.display()
-                );
-                let (remote_info, remote_data) = download::download(&path, token).await?;
-                let remote_data_str = String::from_utf8(remote_data.to_vec());
-                let repo_data_str = String::from_utf8(tokio::fs::read(&local_path).await?);
-                let orig_data_str = String::from_utf8(tokio::fs::read(&meta_path).await?);
-                if remote_data_str.is_ok() && repo_data_str.is_ok() && orig_data_str.is_ok() {
-                    // Try to merge
-                    let mut merged = merge(
-                        orig_data_str.as_ref().unwrap(),
-                        repo_data_str.as_ref().unwrap(),
-                        remote_data_str.as_ref().unwrap(),
-                    );
-
-                    // hash?
-                    merged.set_names("local data", "remote data");
-
-                    let merge_data = format!("{}", merged);
-                    tokio::fs::write(&local_path, &merge_data).await?;
-
-                    if merged.is_successful() {
-                        db::upsert_file(
-                            conn,
-                            &db::FileData::new(
-                                path.to_owned(),
-                                content_hash(merge_data.as_bytes()).to_vec(),
-                            ),
-                        )?;
-                        println!("  - Merged sucessfully!");
-                    } else {
-                        println!("  - Failed to merge...");
-                    }
-                } else {
-                    const CONFLICT_SUFFIX: &str = "CONFLICTED";
-                    let mut conflict_path = local_path.clone();
-                    if let Some(ext) = local_path.extension() {
-                        let mut new_ext = std::ffi::OsString::from(CONFLICT_SUFFIX);
-                        new_ext.push(".");
-                        new_ext.push(ext);
-                        conflict_path.set_extension(new_ext);
-                    } else {
-                        conflict_path.set_extension(CONFLICT_SUFFIX);
-                    }
-                    println!(
-                        "  - Cannot merge binary files. Downloading remote file at {} ...",
-                        conflict_path.display()
-                    );
-                    // change the local hash to the remote value
-                    db::upsert_file(
-                        conn,
-                        &db::FileData::new(
-                            path.to_owned(),
-                            remote_info
-                                .content_hash
-                                .and_then(|s| hex::decode(s).ok())
-                                .unwrap_or_else(|| content_hash(&remote_data).to_vec()),
-                        ),
-                    )?;
-                    tokio::fs::write(&conflict_path, &remote_data).await?;
-                }
-
-                // Update original value
-                tokio::fs::write(&meta_path, &remote_data).await?;
-            }
-            FileStatus::IdenticallyChanged => {
-                println!(
-                    "Both remote and local repo adopt the same change for file {}.",
-                    local_path.display()
-                );
-                // copy data to meta_path
-                tokio::fs::copy(local_path, meta_path).await?;
-            }
-            FileStatus::NotChanged => {
-                // Do nothing. logging?
+                let strategy = strategy.unwrap_or_else(|| strategy_for(config, &path));
+                plan.push(Action::Merge { path, strategy })
             }
-            FileStatus::OnlyLocallyChanged => {
+            FileStatus::IdenticallyChanged => plan.push(Action::MarkSynced { path }),
+            FileStatus::NotChanged | FileStatus::OnlyLocallyChanged => {
                 // Do nothing. logging?
             }
             FileStatus::ToBeRemoved => {
@@ -250,19 +341,35 @@ async fn update_files(
     Ok(local_files)
 }
 
-async fn unlink_files(
+async fn plan_unlink_files(
+    plan: &mut Plan,
     files_to_unlink: &HashMap<String, db::FileData>,
+    placeholders: &HashMap<String, db::Placeholder>,
+    renames: &[(String, String)],
     local_root: &Path,
     config: &Config,
-    conn: &Connection,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    for (path, local_file) in files_to_unlink.into_iter() {
-        let local_path = construct_local_path(path, &config, &local_root);
-        let meta_path = construct_meta_path(path, &config, &local_root);
+    let mut files_to_unlink: Vec<_> = files_to_unlink.values().collect();
+    files_to_unlink.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+    for local_file in files_to_unlink.into_iter() {
+        // The actions are done after the directories are renamed.
+        let path = renamed_path(&local_file.path, renames);
+        if placeholders.contains_key(&local_file.path) {
+            plan.push(Action::Untrack {
+                path,
+                local_hash: None,
+            });
+            continue;
+        }
+
+        let local_path = construct_local_path(&local_file.path, &config, &local_root);
+        let meta_path = construct_meta_path(&local_file.path, &config, &local_root);
 
         let curr_hash = file_hash(&local_path).await.ok();
         let orig_hash = file_hash(&meta_path).await.ok();
         let repo_hash = &local_file.hash;
+        let local_hash = curr_hash.as_ref().map(|h| bytes_to_hex_string(h));
 
         match file_status(
             None,
@@ -270,33 +377,20 @@ async fn unlink_files(
             Some(repo_hash),
             orig_hash.as_ref().map(|h| &h[..]),
         ) {
-            FileStatus::NotSaved => {
-                println!(
-                    "File {} is remotely removed but being edited. Ignoring...",
-                    local_path.display()
-                );
-            }
+            FileStatus::NotSaved => plan.push(Action::Skip {
+                path,
+                reason: "is remotely removed but being edited".to_owned(),
+            }),
             FileStatus::Conflicted => {
-                println!(
-                    "CONFLICT: File {} is remotely removed but locally modified.",
-                    local_path.display()
-                );
-                tokio::fs::remove_file(meta_path).await?;
-                db::delete_file_entry(conn, path)?;
-            }
-            FileStatus::IdenticallyChanged => {
-                println!(
-                    "Both remote and local repo remove file {}.",
-                    local_path.display()
-                );
-                tokio::fs::remove_file(meta_path).await?;
-            }
-            FileStatus::ToBeRemoved => {
-                println!("Removing file {}.", local_path.display());
-                tokio::fs::remove_file(local_path).await?;
-                tokio::fs::remove_file(meta_path).await?;
-                db::delete_file_entry(conn, path)?;
+                plan.push(Action::Conflict {
+                    path: path.clone(),
+                    reason: "is remotely removed but locally modified".to_owned(),
+                    kind: db::Conflict::REMOVED,
+                });
+                plan.push(Action::Untrack { path, local_hash });
             }
+            FileStatus::IdenticallyChanged => plan.push(Action::Untrack { path, local_hash }),
+            FileStatus::ToBeRemoved => plan.push(Action::Delete { path, local_hash }),
             FileStatus::ToBeUpdated
             | FileStatus::ToBeCreated
             | FileStatus::NotChanged
@@ -307,3 +401,54 @@ async fn unlink_files(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_rename_dirs_test() {
+        let mut config = Config::new("/hoge");
+        config.sync_dirs = vec![
+            "/hoge".to_owned(),
+            "/hoge/docs".to_owned(),
+            "/hoge/docs/api".to_owned(),
+            "/hoge/src".to_owned(),
+        ];
+        let remote_dirs = vec![
+            "/hoge".to_owned(),
+            "/hoge/Docs/API".to_owned(),
+            "/hoge/Docs".to_owned(),
+            "/hoge/src".to_owned(),
+        ];
+
+        let mut plan = Plan::new("/hoge");
+        let renames = plan_rename_dirs(
+            &mut plan,
+            &remote_dirs,
+            Path::new("dsync_rename_dirs_test"),
+            &config,
+        );
+        assert_eq!(
+            plan.actions,
+            vec![
+                Action::RenameDir {
+                    from: "/hoge/docs".to_owned(),
+                    to: "/hoge/Docs".to_owned(),
+                },
+                Action::RenameDir {
+                    from: "/hoge/Docs/api".to_owned(),
+                    to: "/hoge/Docs/API".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            renamed_path("/hoge/docs/api/index.md", &renames),
+            "/hoge/Docs/API/index.md"
+        );
+        assert_eq!(
+            renamed_path("/hoge/docsx/a.md", &renames),
+            "/hoge/docsx/a.md"
+        );
+    }
+}
@@ -1,76 +1,79 @@
+use super::plan::{execute, Action, Plan};
 use super::{
-    construct_local_path, construct_meta_path, create_dirs, create_metadir_for_file, download_file,
-    file_status, load_config, save_config, visit_all_dirs, Config, FileStatus,
+    connect_db, construct_local_path, construct_meta_path, ensure_no_conflicts, load_config,
+    save_config,
 };
-use crate::files::{delete, download, get_metadata, FileInfo};
-use crate::ignore::parce_ignore;
-use crate::{content_hash, db, file_hash};
-use diffmerge::merge;
-use rusqlite::Connection;
-use std::collections::HashMap;
+use crate::files::get_metadata;
+use crate::{db, file_hash};
 
 use std::path::Path;
 
 pub async fn push(
     local_root: impl AsRef<Path>,
+    dry_run: bool,
+    plan_out: Option<&str>,
     token: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let ignore_filter = parce_ignore().await?;
     let local_root = local_root.as_ref();
-    let config = load_config(local_root).await.map_err(|_| {
+    let mut config = load_config(local_root).await.map_err(|_| {
         format!(
             "Directory {} is not a correct dsync repo.",
             local_root.display()
         )
     })?;
-    let conn = db::connect(local_root)?;
+    let conn = connect_db(&config, local_root)?;
+    ensure_no_conflicts(&conn)?;
     let updates = db::list_files_to_update(&conn)?;
 
+    let mut plan = Plan::new(&config.remote_path);
     for update in updates.iter() {
-        let path = &update.path;
-        let local_path = construct_local_path(path, &config, &local_root);
-        let meta_path = construct_meta_path(path, &config, &local_root);
+        let path = update.path.clone();
+        let local_path = construct_local_path(&path, &config, &local_root);
+        let meta_path = construct_meta_path(&path, &config, &local_root);
 
-        let repo_hash = db::find_file(&conn, path).ok().map(|fd| fd.hash);
-        let curr_hash = file_hash(&local_path).await.ok();
-        let orig_hash = file_hash(&meta_path).await.ok();
-        let remote_hash = get_metadata::get_metadata(path, token)
-            .await
-            .ok()
+        let repo_hash = db::find_file(&conn, &path).ok().map(|fd| fd.hash);
+        let curr_hash = file_hash(&local_path).await.ok().map(|h| h.to_vec());
+        let orig_hash = file_hash(&meta_path).await.ok().map(|h| h.to_vec());
+        let remote_info = get_metadata::get_metadata(&path, token).await.ok();
+        let remote_hash = remote_info
+            .as_ref()
             .and_then(|i| i.content_hash.as_ref().and_then(|s| hex::decode(s).ok()));
 
-        if repo_hash != curr_hash {
-            println!("The file {} is edited after added. Ignoring...", path);
+        let removing = update.operation == db::FileUpdate::REMOVE;
+        if (removing && curr_hash.is_some()) || (!removing && repo_hash != curr_hash) {
+            plan.push(Action::Skip {
+                path,
+                reason: "is edited after added".to_owned(),
+            });
             continue;
         }
 
         if orig_hash != remote_hash {
-            println!(
-                "The file {} is remotely updated. Pull first. Ignoring...",
-                path
-            );
+            plan.push(Action::Skip {
+                path,
+                reason: "is remotely updated. Pull first".to_owned(),
+            });
             continue;
         }
 
         match update.operation {
-            db::FileUpdate::ADD => {
-                create_metadir_for_file(path, config, local_root);
-                tokio::fs::copy(local_path, meta_path).await?;
-            }
-            db::FileUpdate::REMOVE => {
-                delete::delete(path, token).await?;
-                tokio::fs::remove_file(meta_path).await?;
-                // TODO remove meta dir?
-            }
-            db::FileUpdate::UPDATE => {
-                create_metadir_for_file(path, config, local_root);
-                tokio::fs::copy(local_path, meta_path).await?;
-            }
+            db::FileUpdate::ADD | db::FileUpdate::UPDATE => plan.push(Action::Upload {
+                path,
+                rev: remote_info.map(|i| i.rev),
+            }),
+            db::FileUpdate::REMOVE => plan.push(Action::DeleteRemote { path }),
             _ => {
                 panic!("The repository is broken");
             }
         }
     }
 
-    db::clear_all_files_to_update(&conn)?;
+    if !plan.review(dry_run, plan_out).await? {
+        return Ok(());
+    }
+
+    execute(&plan, &mut config, local_root, &conn, token).await?;
+    save_config(&config, local_root).await?;
+
+    Ok(())
 }
@@ -0,0 +1,329 @@
+use super::{construct_meta_path, is_ignore_file, Config, FETCH_DIR};
+use crate::files::list_folder::{list_folder_continue, list_folder_recursive, Entry};
+use crate::files::{download, is_api_error, FileInfo};
+use crate::ignore::{Ignore, RepoPath, IGNORE_FILE};
+use crate::{bytes_to_hex_string, content_hash, db, file_hash};
+use rusqlite::Connection;
+use tokio::fs;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Updates the saved listing of the remote files.
+/// The listing continues from the saved cursor if possible.
+pub(crate) async fn refresh(
+    config: &Config,
+    conn: &mut Connection,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    for root in config.sync_roots().into_iter() {
+        let key = root.to_lowercase();
+        let continued = if let Ok(cursor) = db::find_cursor(conn, &key) {
+            match list_folder_continue(&cursor, token).await {
+                Ok(result) => Some(result),
+                Err(e) if is_api_error(&*e, "reset") => None,
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+
+        let (entries, cursor, reset) = match continued {
+            Some((entries, cursor)) => (entries, Some(cursor), false),
+            None => match list_folder_recursive(&root, token).await {
+                Ok((entries, cursor)) => (entries, Some(cursor), true),
+                Err(e) if is_api_error(&*e, "path/not_found") => {
+                    println!("Directory {} does not exist. Ignoring...", root);
+                    (vec![], None, true)
+                }
+                Err(e) => return Err(e),
+            },
+        };
+
+        let entries = entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Entry::File(info) => Some(db::RemoteEntry::File {
+                    path_lower: info.path_lower.clone()?,
+                    info: serde_json::to_string(&info).ok()?,
+                }),
+                Entry::Folder {
+                    path_lower,
+                    path_display,
+                    ..
+                } => Some(db::RemoteEntry::Dir {
+                    path_lower: path_lower?,
+                    path: path_display?,
+                }),
+                Entry::Deleted { path_lower, .. } => Some(db::RemoteEntry::Deleted {
+                    path_lower: path_lower?,
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        db::save_remote_entries(conn, &key, &entries, cursor.as_deref(), reset)?;
+    }
+
+    save_remote_ignores(config, conn, token).await
+}
+
+/// Downloads the remote ignore files in the saved listing which changed since the last refresh.
+async fn save_remote_ignores(
+    config: &Config,
+    conn: &Connection,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut saved: HashMap<_, _> = db::list_remote_ignores(conn)?
+        .into_iter()
+        .map(|(dir, hash, _)| (dir, hash))
+        .collect();
+
+    for info in db::list_remote_files(conn)?.into_iter() {
+        let info: FileInfo = serde_json::from_str(&info)?;
+        let (path, hash) = match (&info.path_display, &info.content_hash) {
+            (Some(path), Some(hash)) if is_ignore_file(path) => (path, hash),
+            _ => continue,
+        };
+        let dir = config
+            .repo_path(path)
+            .ancestors()
+            .next()
+            .unwrap_or_else(RepoPath::root);
+        if saved.remove(dir.as_str()).as_ref() == Some(hash) {
+            continue;
+        }
+
+        let (_, data) = download::download(path, token).await?;
+        db::upsert_remote_ignore(conn, dir.as_str(), hash, &data)?;
+    }
+
+    for dir in saved.keys() {
+        db::delete_remote_ignore(conn, dir)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the rules of the ignore file in `dir` to `ignore_filter`.
+/// The local ignore file is used if it exists, otherwise the remote one saved by `refresh`.
+fn add_dir_ignore(
+    ignore_filter: &mut Ignore,
+    dir: &RepoPath,
+    config: &Config,
+    local_root: &Path,
+    remote_ignores: &HashMap<RepoPath, Vec<u8>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut local_ignore = local_root.join(dir.as_str());
+    local_ignore.push(IGNORE_FILE);
+    if local_ignore.exists() {
+        // The local ignore file at the root is already read.
+        if !dir.is_root() {
+            let source = local_ignore.to_string_lossy();
+            ignore_filter.add_rules(dir, &source, std::fs::read(&local_ignore)?)?;
+        }
+    } else if let Some(content) = remote_ignores.get(dir) {
+        let source = config.remote_path_of(dir.join(IGNORE_FILE).as_str());
+        ignore_filter.add_rules(dir, &source, content.clone())?;
+    }
+    Ok(())
+}
+
+fn load_remote_ignores(conn: &Connection) -> Result<HashMap<RepoPath, Vec<u8>>, Box<dyn Error>> {
+    Ok(db::list_remote_ignores(conn)?
+        .into_iter()
+        .map(|(dir, _, content)| (RepoPath::new(&dir), content))
+        .collect())
+}
+
+/// Adds the rules of the ignore files in the remote directories to `ignore_filter`.
+/// If a directory has a local ignore file, it is used instead of the remote one.
+pub(crate) fn with_dir_ignores(
+    ignore_filter: &Ignore,
+    config: &Config,
+    conn: &Connection,
+    local_root: &Path,
+) -> Result<Ignore, Box<dyn Error>> {
+    let mut ignore_filter = ignore_filter.clone();
+    let remote_ignores = load_remote_ignores(conn)?;
+
+    let mut dirs: Vec<_> = db::list_remote_dirs(conn)?
+        .iter()
+        .map(|dir| config.repo_path(dir))
+        .chain(remote_ignores.keys().cloned())
+        .collect();
+    // Parents come first.
+    dirs.sort_unstable();
+    dirs.dedup();
+
+    for dir in dirs.iter() {
+        add_dir_ignore(&mut ignore_filter, dir, config, local_root, &remote_ignores)?;
+    }
+
+    Ok(ignore_filter)
+}
+
+/// Adds the rules of the ignore files in the parent directories of `path` like `with_dir_ignores`.
+pub(crate) fn with_parent_ignores(
+    ignore_filter: &Ignore,
+    path: &RepoPath,
+    config: &Config,
+    conn: &Connection,
+    local_root: &Path,
+) -> Result<Ignore, Box<dyn Error>> {
+    let mut ignore_filter = ignore_filter.clone();
+    let remote_ignores = load_remote_ignores(conn)?;
+
+    let mut dirs: Vec<_> = path.ancestors().collect();
+    dirs.push(RepoPath::root());
+    dirs.reverse();
+    for dir in dirs.iter() {
+        add_dir_ignore(&mut ignore_filter, dir, config, local_root, &remote_ignores)?;
+    }
+
+    Ok(ignore_filter)
+}
+
+/// Checks if any of the parent directories of the remote path is ignored.
+/// As in `visit_all_dirs`, the contents of an ignored directory are not listed.
+fn in_ignored_dir(ignore_filter: &Ignore, path: &RepoPath) -> bool {
+    path.ancestors().any(|p| ignore_filter.is_ignored(&p, true))
+}
+
+/// Loads the saved listing like `visit_repo_dirs`.
+/// The ignore files in the subdirectories are applied as well.
+pub(crate) fn load_listing(
+    config: &Config,
+    conn: &Connection,
+    local_root: &Path,
+    ignore_filter: &Ignore,
+) -> Result<(Vec<String>, Vec<FileInfo>), Box<dyn Error>> {
+    let ignore_filter = &with_dir_ignores(ignore_filter, config, conn, local_root)?;
+    let n_root = config.remote_path.trim_end_matches('/').len();
+    let mut dirs = vec![config.remote_path.clone()];
+    let push_dir = |dirs: &mut Vec<String>, dir: &str| {
+        if !dirs.iter().any(|d| d.to_lowercase() == dir.to_lowercase()) {
+            dirs.push(dir.to_owned());
+        }
+    };
+
+    if config.sparse.is_some() {
+        for root in config.sync_roots().into_iter() {
+            for (i, _) in root.match_indices('/') {
+                if i > n_root {
+                    push_dir(&mut dirs, &root[..i]);
+                }
+            }
+        }
+    }
+
+    for dir in db::list_remote_dirs(conn)?.into_iter() {
+        if config.is_selected(&dir) && !in_ignored_dir(ignore_filter, &config.repo_path(&dir)) {
+            push_dir(&mut dirs, &dir);
+        }
+    }
+
+    let mut files = vec![];
+    for info in db::list_remote_files(conn)?.into_iter() {
+        let info: FileInfo = serde_json::from_str(&info)?;
+        if let Some(ref path) = info.path_display {
+            // The parent directories are checked as well.
+            if config.is_selected(path) && !ignore_filter.is_ignored(&config.repo_path(path), false)
+            {
+                files.push(info);
+            }
+        }
+    }
+    files.sort_unstable_by(|a, b| a.path_display.cmp(&b.path_display));
+
+    Ok((dirs, files))
+}
+
+pub(crate) fn fetched_path(local_root: &Path, hash: &str) -> PathBuf {
+    let mut path = local_root.to_owned();
+    path.push(FETCH_DIR);
+    path.push(hash);
+    path
+}
+
+/// Downloads the remote files which differ from the `.dsync` copies into `FETCH_DIR`.
+/// The obsolete fetched files are removed.
+pub(crate) async fn fetch_files(
+    files: &[FileInfo],
+    config: &Config,
+    local_root: &Path,
+    conn: &Connection,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let local_files = db::list_files(conn)?;
+    let placeholders = db::list_placeholders(conn)?;
+    let mut wanted = HashMap::new();
+
+    for file in files.iter() {
+        let (path, hash) = match (&file.path_display, &file.content_hash) {
+            (Some(path), Some(hash)) => (path, hash),
+            _ => continue,
+        };
+        if placeholders.contains_key(path) {
+            continue;
+        }
+        let remote_hash = hex::decode(hash).ok();
+        if local_files.get(path).map(|f| &f.hash) == remote_hash.as_ref() {
+            continue;
+        }
+        let meta_path = construct_meta_path(path, config, local_root);
+        if file_hash(&meta_path).await.ok().map(|h| h.to_vec()) == remote_hash {
+            continue;
+        }
+
+        let fetched = fetched_path(local_root, hash);
+        wanted.insert(hash.clone(), ());
+        if fetched.exists() {
+            continue;
+        }
+
+        println!("Fetching {} ...", path);
+        let (_, data) = download::download(path, token).await?;
+        let hash = bytes_to_hex_string(&content_hash(&data));
+        fs::create_dir_all(fetched.parent().unwrap()).await?;
+        fs::write(fetched_path(local_root, &hash), &data).await?;
+        wanted.insert(hash, ());
+    }
+
+    let mut fetch_dir = local_root.to_owned();
+    fetch_dir.push(FETCH_DIR);
+    if let Ok(mut reads) = fs::read_dir(&fetch_dir).await {
+        while let Some(entry) = reads.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !wanted.contains_key(&name) {
+                fs::remove_file(entry.path()).await.ok();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the remote file, using the fetched data if available.
+pub(crate) async fn download_remote(
+    remote_path: &str,
+    local_root: &Path,
+    conn: &Connection,
+    token: &str,
+) -> Result<(FileInfo, Vec<u8>), Box<dyn Error>> {
+    if let Ok(info) = db::find_remote_file(conn, &remote_path.to_lowercase()) {
+        let info: FileInfo = serde_json::from_str(&info)?;
+        if let Some(ref hash) = info.content_hash {
+            let fetched = fetched_path(local_root, hash);
+            if let Ok(data) = fs::read(&fetched).await {
+                if &bytes_to_hex_string(&content_hash(&data)) == hash {
+                    fs::remove_file(&fetched).await.ok();
+                    return Ok((info, data));
+                }
+            }
+        }
+    }
+
+    let (info, data) = download::download(remote_path, token).await?;
+    Ok((info, data.to_vec()))
+}
@@ -0,0 +1,47 @@
+use super::{connect_db, construct_meta_path, construct_new_remote_path, is_in_dir, load_config};
+use crate::{db, file_hash};
+
+use std::path::Path;
+
+/// Unstages the updates under `target`, or all the updates if `target` is `None`.
+pub async fn reset(
+    target: Option<&str>,
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let mut conn = connect_db(&config, local_root)?;
+    let prefix = if let Some(target) = target {
+        construct_new_remote_path(target, &config, local_root)?
+    } else {
+        config.remote_path.clone()
+    };
+
+    let updates: Vec<_> = db::list_files_to_update(&conn)?
+        .into_iter()
+        .filter(|u| is_in_dir(&u.path, &prefix))
+        .collect();
+
+    for update in updates.iter() {
+        let path = &update.path;
+        let meta_path = construct_meta_path(path, &config, local_root);
+
+        if let Ok(orig_hash) = file_hash(&meta_path).await {
+            db::upsert_file(&conn, &db::FileData::new(path.clone(), orig_hash.to_vec()))?;
+        } else {
+            // Never synced. Forget the file.
+            db::delete_file_entry(&conn, path)?;
+        }
+        println!("Unstaged {}", path);
+    }
+
+    let paths: Vec<_> = updates.iter().map(|u| &*u.path).collect();
+    db::clear_files_to_update(&mut conn, &paths)?;
+
+    Ok(())
+}
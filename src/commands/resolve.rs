@@ -0,0 +1,153 @@
+use super::merge::clear_conflict;
+use super::{connect_db, construct_local_path, construct_new_remote_path, is_in_dir, load_config};
+use crate::db;
+use tokio::fs;
+
+use std::path::Path;
+
+/// Counts the entries in the directory of `local_path` with the same name ignoring case.
+fn count_case_variants(local_path: &Path) -> usize {
+    let (dir, name) = match (local_path.parent(), local_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy().to_lowercase()),
+        _ => return 0,
+    };
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().to_lowercase() == name)
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+/// Checks if the text still contains the conflict markers written by merge.
+pub(crate) fn has_conflict_markers(text: &str) -> bool {
+    text.lines().any(|line| {
+        line.starts_with("<<<<<<<")
+            || line.starts_with(">>>>>>>")
+            || line.starts_with("|||||||")
+            || line.trim_end() == "======="
+    })
+}
+
+/// Marks the conflicted files under `targets` as resolved.
+pub async fn resolve(
+    targets: &[impl AsRef<Path>],
+    local_root: impl AsRef<Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let conflicts = db::list_conflicts(&conn)?;
+    let copies = db::list_conflicted_copies(&conn)?;
+
+    for target in targets.iter() {
+        let target = target.as_ref();
+        let prefix = construct_new_remote_path(target, &config, local_root)?;
+        let conflicts: Vec<_> = conflicts
+            .iter()
+            .filter(|c| is_in_dir(&c.path, &prefix))
+            .collect();
+
+        if conflicts.is_empty() {
+            println!("No conflict found in {}.", target.display());
+        }
+
+        for conflict in conflicts.into_iter() {
+            let local_path = construct_local_path(&conflict.path, &config, local_root);
+            match conflict.kind {
+                db::Conflict::MARKERS => {
+                    if let Ok(data) = fs::read(&local_path).await {
+                        if has_conflict_markers(&String::from_utf8_lossy(&data)) {
+                            println!(
+                                "File {} still has conflict markers. Ignoring...",
+                                local_path.display()
+                            );
+                            continue;
+                        }
+                    }
+                }
+                db::Conflict::COPY => {
+                    if let Some(ref copy_path) = conflict.copy_path {
+                        if Path::new(copy_path).exists() {
+                            println!(
+                                "Remote copy {} still exists. Remove it after taking the changes.",
+                                copy_path
+                            );
+                            continue;
+                        }
+                    }
+                }
+                db::Conflict::REMOTE_COPY => {
+                    let copies: Vec<_> = copies
+                        .iter()
+                        .filter(|(_, original)| *original == &conflict.path)
+                        .map(|(copy, _)| copy)
+                        .collect();
+                    let remaining: Vec<_> = copies
+                        .iter()
+                        .map(|copy| construct_local_path(copy, &config, local_root))
+                        .filter(|path| path.exists())
+                        .collect();
+                    if !remaining.is_empty() {
+                        for path in remaining.iter() {
+                            println!(
+                                "Conflicted copy {} still exists. Remove it after taking the changes.",
+                                path.display()
+                            );
+                        }
+                        continue;
+                    }
+                    // The copies are removed from Dropbox by the next push.
+                    for copy in copies.into_iter() {
+                        db::add_update(
+                            &conn,
+                            &db::FileUpdate::new(copy.clone(), db::FileUpdate::REMOVE),
+                        )?;
+                    }
+                }
+                db::Conflict::CASE_COLLISION => {
+                    // Renamed by the next pull once the other one is gone.
+                    if count_case_variants(&local_path) > 1 {
+                        println!(
+                            "{} still differs only in case from another local file. Rename or remove one of them.",
+                            local_path.display()
+                        );
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+
+            clear_conflict(&conflict.path, &config, local_root, &conn).await?;
+            println!(
+                "Resolved {}. Use add command to stage it.",
+                local_path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflict_markers_test() {
+        assert!(!has_conflict_markers("hoge\nfuga\n"));
+        assert!(!has_conflict_markers("hoge\n========\nfuga\n"));
+        assert!(has_conflict_markers(
+            "<<<<<<< local data\nhoge\n=======\nfuga\n>>>>>>> remote data\n"
+        ));
+        assert!(has_conflict_markers("hoge\n=======\nfuga\n"));
+        assert!(has_conflict_markers(">>>>>>> remote data"));
+    }
+}
@@ -0,0 +1,206 @@
+use super::{
+    connect_db, construct_local_path, construct_meta_path, construct_new_remote_path,
+    create_metadir_for_file, download_file, load_config, Config,
+};
+use crate::files::list_revisions::{list_revisions, Revisions};
+use crate::files::{download, restore, FileInfo};
+use crate::{db, file_hash};
+use rusqlite::Connection;
+use tokio::fs;
+
+use std::path::Path;
+
+/// Maximum number of revisions Dropbox returns at once.
+pub(crate) const MAX_REVISIONS: u32 = 100;
+
+/// Maximum number of pages of revisions listed while looking for a revision before a time.
+const MAX_REVISION_PAGES: usize = 10;
+
+pub async fn restore(
+    target: &str,
+    rev: Option<&str>,
+    before: Option<&str>,
+    local_only: bool,
+    local_root: impl AsRef<Path>,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let mut conn = connect_db(&config, local_root)?;
+    let remote_path = construct_new_remote_path(target, &config, local_root)?;
+
+    let rev = match (rev, before) {
+        (Some(rev), _) => rev.to_owned(),
+        (None, Some(before)) => {
+            let (revisions, truncated) = revisions_until(&remote_path, before, token).await?;
+            match revision_before(&revisions.entries, before) {
+                Some(e) => e.rev.clone(),
+                None if truncated => {
+                    return Err(format!(
+                        "History of {} is truncated before any revision before {} is found.",
+                        remote_path, before
+                    )
+                    .into())
+                }
+                None => {
+                    return Err(
+                        format!("No revision of {} found before {}.", remote_path, before).into(),
+                    )
+                }
+            }
+        }
+        (None, None) => return Err("Either --rev or --before must be specified.".into()),
+    };
+
+    let local_path = construct_local_path(&remote_path, &config, local_root);
+    let meta_path = construct_meta_path(&remote_path, &config, local_root);
+
+    if local_only {
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let (_, data) = download::download(&format!("rev:{}", rev), token).await?;
+        fs::write(&local_path, data).await?;
+        println!(
+            "Revision {} is written to {}. Use add command to stage it.",
+            rev,
+            local_path.display()
+        );
+        return Ok(());
+    }
+
+    let curr_hash = file_hash(&local_path).await.ok();
+    let orig_hash = file_hash(&meta_path).await.ok();
+    if curr_hash != orig_hash {
+        return Err(format!(
+            "File {} has local changes. Use --local-only or discard them first.",
+            local_path.display()
+        )
+        .into());
+    }
+
+    restore_file(&remote_path, &rev, &config, local_root, &mut conn, token).await
+}
+
+/// Restores `remote_path` to `rev` on Dropbox and updates the local copies accordingly.
+/// The caller must ensure that the file has no local changes.
+pub(crate) async fn restore_file(
+    remote_path: &str,
+    rev: &str,
+    config: &Config,
+    local_root: &Path,
+    conn: &mut Connection,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Restoring {} to revision {} ...", remote_path, rev);
+    restore::restore(remote_path, rev, token).await?;
+    let local_path = construct_local_path(remote_path, config, local_root);
+    if let Some(parent) = local_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    create_metadir_for_file(remote_path, config, local_root).await?;
+    download_file(remote_path, config, local_root, conn, token).await?;
+    db::clear_files_to_update(conn, &[remote_path])?;
+
+    Ok(())
+}
+
+/// Normalizes a timestamp given by the user into the format of `server_modified`,
+/// e.g. `2020-05-01T12:34:56Z`.
+pub(crate) fn normalize_timestamp(timestamp: &str) -> String {
+    let timestamp = timestamp.trim().replace(' ', "T");
+    match timestamp.len() {
+        10 => format!("{}T00:00:00Z", timestamp), // date only
+        16 => format!("{}:00Z", timestamp),       // without seconds
+        19 => format!("{}Z", timestamp),          // without time zone
+        _ => timestamp,
+    }
+}
+
+/// Lists the revisions of `remote_path` page by page, newest first,
+/// until a revision modified at or before `timestamp` is found.
+/// Also returns whether the listing is given up before reaching the oldest revision.
+pub(crate) async fn revisions_until(
+    remote_path: &str,
+    timestamp: &str,
+    token: &str,
+) -> Result<(Revisions, bool), Box<dyn std::error::Error>> {
+    let mut revisions = list_revisions(remote_path, MAX_REVISIONS, None, token).await?;
+    let mut pages = 1;
+    while revisions.has_more && revision_before(&revisions.entries, timestamp).is_none() {
+        if pages == MAX_REVISION_PAGES {
+            return Ok((revisions, true));
+        }
+        let before_rev = match revisions.entries.last() {
+            Some(last) => last.rev.clone(),
+            None => break,
+        };
+        let older = list_revisions(remote_path, MAX_REVISIONS, Some(&before_rev), token).await?;
+        revisions.entries.extend(older.entries.into_iter());
+        revisions.has_more = older.has_more;
+        pages += 1;
+    }
+    Ok((revisions, false))
+}
+
+/// Finds the latest revision modified at or before `timestamp`.
+pub(crate) fn revision_before<'a>(
+    entries: &'a [FileInfo],
+    timestamp: &str,
+) -> Option<&'a FileInfo> {
+    let timestamp = normalize_timestamp(timestamp);
+    entries
+        .iter()
+        .filter(|e| e.server_modified <= timestamp)
+        .max_by(|a, b| a.server_modified.cmp(&b.server_modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(rev: &str, server_modified: &str) -> FileInfo {
+        FileInfo {
+            name: "a.txt".to_owned(),
+            id: "id:a".to_owned(),
+            client_modified: server_modified.to_owned(),
+            server_modified: server_modified.to_owned(),
+            rev: rev.to_owned(),
+            size: 0,
+            path_lower: Some("/a.txt".to_owned()),
+            path_display: Some("/a.txt".to_owned()),
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn revision_before_test() {
+        assert_eq!(normalize_timestamp("2020-05-01"), "2020-05-01T00:00:00Z");
+        assert_eq!(
+            normalize_timestamp("2020-05-01 12:34"),
+            "2020-05-01T12:34:00Z"
+        );
+        assert_eq!(
+            normalize_timestamp("2020-05-01T12:34:56Z"),
+            "2020-05-01T12:34:56Z"
+        );
+
+        let entries = vec![
+            revision("3", "2020-05-03T00:00:00Z"),
+            revision("2", "2020-05-02T00:00:00Z"),
+            revision("1", "2020-05-01T00:00:00Z"),
+        ];
+        assert_eq!(revision_before(&entries, "2020-04-30"), None);
+        assert_eq!(revision_before(&entries, "2020-05-01").unwrap().rev, "1");
+        assert_eq!(
+            revision_before(&entries, "2020-05-02 12:00").unwrap().rev,
+            "2"
+        );
+        assert_eq!(revision_before(&entries, "2021-01-01").unwrap().rev, "3");
+    }
+}
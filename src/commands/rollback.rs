@@ -0,0 +1,174 @@
+use super::remote::with_dir_ignores;
+use super::restore::{normalize_timestamp, restore_file, revision_before, revisions_until};
+use super::{
+    confirm, connect_db, construct_local_path, construct_meta_path, construct_remote_path,
+    is_in_dir, load_config,
+};
+use crate::files::list_folder::{list_folder_with_deleted, Entry};
+use crate::files::{delete, is_api_error};
+use crate::ignore::parce_repo_ignore;
+use crate::{db, file_hash};
+use tokio::fs;
+
+use std::path::Path;
+
+enum Action {
+    Restore {
+        path: String,
+        rev: String,
+        server_modified: String,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+pub async fn rollback(
+    to: &str,
+    target: Option<&str>,
+    yes: bool,
+    local_root: impl AsRef<Path>,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let mut conn = connect_db(&config, local_root)?;
+    let prefix = if let Some(target) = target {
+        construct_remote_path(target, &config, local_root)?
+    } else {
+        config.remote_path.clone()
+    };
+
+    let tracked = db::list_files_by_lower(&conn)?;
+    let mut paths: Vec<_> = tracked
+        .values()
+        .map(|file| file.path.clone())
+        .filter(|path| is_in_dir(path, &prefix))
+        .collect();
+
+    // The files deleted remotely are not tracked any more.
+    let ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+    let ignore_filter = with_dir_ignores(&ignore_filter, &config, &conn, local_root)?;
+    let entries = match list_folder_with_deleted(&prefix, token).await {
+        Ok(entries) => entries,
+        Err(e) if is_api_error(&*e, "path/not_found") || is_api_error(&*e, "path/not_folder") => {
+            vec![]
+        }
+        Err(e) => return Err(e),
+    };
+    for entry in entries.into_iter() {
+        if let Entry::Deleted {
+            path_display: Some(path),
+            ..
+        } = entry
+        {
+            if !tracked.contains_key(&db::path_lower(&path))
+                && config.is_selected(&path)
+                && !ignore_filter.is_ignored(&config.repo_path(&path), false)
+            {
+                paths.push(path);
+            }
+        }
+    }
+    paths.sort_unstable();
+
+    println!("Checking revisions of {} files ...", paths.len());
+    let to = normalize_timestamp(to);
+    let mut plan = vec![];
+    for path in paths.into_iter() {
+        let (revisions, truncated) = match revisions_until(&path, &to, token).await {
+            Ok(revisions) => revisions,
+            // A deleted entry may be a directory.
+            Err(e) if is_api_error(&*e, "path/not_file") => continue,
+            Err(e) => return Err(e),
+        };
+        let current = if revisions.is_deleted {
+            None
+        } else {
+            revisions.entries.first()
+        };
+        let deleted_before = revisions.is_deleted
+            && revisions
+                .server_deleted
+                .as_deref()
+                .map_or(false, |deleted| deleted <= to.as_str());
+        let target = if deleted_before {
+            None
+        } else {
+            revision_before(&revisions.entries, &to)
+        };
+
+        match (current, target) {
+            (Some(current), Some(target)) if current.rev == target.rev => (),
+            (_, Some(target)) => plan.push(Action::Restore {
+                path,
+                rev: target.rev.clone(),
+                server_modified: target.server_modified.clone(),
+            }),
+            // The file may have existed before the oldest revision listed.
+            (Some(_), None) if truncated => {
+                println!("History of {} is truncated. Skipping...", path)
+            }
+            (Some(_), None) => plan.push(Action::Delete { path }),
+            (None, None) => (),
+        }
+    }
+
+    if plan.is_empty() {
+        println!("Nothing to roll back.");
+        return Ok(());
+    }
+
+    for action in plan.iter() {
+        match action {
+            Action::Restore {
+                path,
+                rev,
+                server_modified,
+            } => println!("  restore {} to {} ({})", path, rev, server_modified),
+            Action::Delete { path } => println!("  delete  {}", path),
+        }
+    }
+
+    if !yes && !confirm("Apply the plan above?").await? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    for action in plan.iter() {
+        let path = match action {
+            Action::Restore { path, .. } | Action::Delete { path } => path,
+        };
+        let local_path = construct_local_path(path, &config, local_root);
+        let meta_path = construct_meta_path(path, &config, local_root);
+        let curr_hash = file_hash(&local_path).await.ok();
+        let orig_hash = file_hash(&meta_path).await.ok();
+        if curr_hash != orig_hash {
+            println!(
+                "File {} has local changes. Ignoring...",
+                local_path.display()
+            );
+            continue;
+        }
+
+        match action {
+            Action::Restore { path, rev, .. } => {
+                restore_file(path, rev, &config, local_root, &mut conn, token).await?;
+            }
+            Action::Delete { path } => {
+                println!("Removing file {}.", local_path.display());
+                delete::delete(path, token).await?;
+                fs::remove_file(&local_path).await.ok();
+                fs::remove_file(&meta_path).await.ok();
+                db::delete_file_entry(&conn, path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
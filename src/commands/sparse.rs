@@ -0,0 +1,169 @@
+use super::{
+    connect_db, construct_local_path, construct_meta_path, is_in_dir, load_config, save_config,
+    Config,
+};
+use crate::{db, file_hash};
+use rusqlite::Connection;
+use tokio::fs;
+
+use std::error::Error;
+use std::path::Path;
+
+/// Normalizes a subtree given by the user into the form saved in `Config::sparse`.
+pub(crate) fn normalize_sparse_dir(dir: &str) -> String {
+    dir.trim_start_matches("./").trim_matches('/').to_owned()
+}
+
+pub async fn add(dir: &str, local_root: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let local_root = local_root.as_ref();
+    let mut config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let dir = normalize_sparse_dir(dir);
+    if dir == "" {
+        return Err("Specify a directory in the repo.".into());
+    }
+
+    let narrowing = config.sparse.is_none();
+    let mut sparse = config.sparse.clone().unwrap_or_default();
+    if sparse.iter().any(|s| is_in_dir(&dir, s)) {
+        println!("Directory {} is already selected.", dir);
+        return Ok(());
+    }
+    sparse.retain(|s| !is_in_dir(s, &dir));
+    sparse.push(dir.clone());
+    sparse.sort_unstable();
+    config.sparse = Some(sparse);
+
+    if narrowing {
+        clean_unselected(&mut config, local_root, &conn).await?;
+    }
+    save_config(&config, local_root).await?;
+    println!("Directory {} is selected. Run pull to download it.", dir);
+
+    Ok(())
+}
+
+pub async fn remove(dir: &str, local_root: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let local_root = local_root.as_ref();
+    let mut config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let conn = connect_db(&config, local_root)?;
+    let dir = normalize_sparse_dir(dir);
+
+    let mut sparse = config
+        .sparse
+        .clone()
+        .ok_or_else(|| "The whole repo is selected. Use sparse add first.".to_owned())?;
+    let n_sparse = sparse.len();
+    sparse.retain(|s| s != &dir);
+    if sparse.len() == n_sparse {
+        return Err(format!("Directory {} is not selected.", dir).into());
+    }
+    if sparse.is_empty() {
+        return Err(format!(
+            "Directory {} is the only selected directory. Select another one first.",
+            dir
+        )
+        .into());
+    }
+    config.sparse = Some(sparse);
+
+    clean_unselected(&mut config, local_root, &conn).await?;
+    save_config(&config, local_root).await?;
+
+    Ok(())
+}
+
+pub async fn list(local_root: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+
+    if let Some(sparse) = config.sparse {
+        for dir in sparse.iter() {
+            println!("{}", dir);
+        }
+    } else {
+        println!("(whole repo)");
+    }
+
+    Ok(())
+}
+
+/// Removes the local copies outside of the selected subtrees.
+/// The files with local changes are kept as they are.
+async fn clean_unselected(
+    config: &mut Config,
+    local_root: &Path,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let staged: Vec<_> = db::list_files_to_update(conn)?
+        .into_iter()
+        .map(|u| u.path)
+        .collect();
+    let mut files: Vec<_> = db::list_files(conn)?
+        .into_iter()
+        .filter(|(path, _)| !config.is_selected(path))
+        .collect();
+    files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    for (path, file) in files.into_iter() {
+        let local_path = construct_local_path(&path, config, local_root);
+        let meta_path = construct_meta_path(&path, config, local_root);
+        let curr_hash = file_hash(&local_path).await.ok();
+        let orig_hash = file_hash(&meta_path).await.ok();
+
+        let changed = curr_hash.is_some()
+            && (curr_hash.as_ref().map(|h| &h[..]) != Some(&file.hash[..])
+                || curr_hash != orig_hash);
+        if changed || staged.contains(&path) {
+            println!(
+                "File {} has local changes. Keeping it...",
+                local_path.display()
+            );
+            continue;
+        }
+
+        println!("Removing file {}.", local_path.display());
+        fs::remove_file(&local_path).await.ok();
+        fs::remove_file(&meta_path).await.ok();
+        db::delete_file_entry(conn, &path)?;
+    }
+
+    let mut dirs: Vec<_> = config
+        .sync_dirs
+        .iter()
+        .filter(|d| !config.is_selected(d) && !config.is_sparse_ancestor(d))
+        .cloned()
+        .collect();
+    dirs.sort_unstable_by(|a, b| b.cmp(a)); // children first
+
+    for dir in dirs.into_iter() {
+        let local_path = construct_local_path(&dir, config, local_root);
+        let meta_path = construct_meta_path(&dir, config, local_root);
+        fs::remove_dir(&meta_path).await.ok();
+        if fs::remove_dir(&local_path).await.is_ok() || !local_path.exists() {
+            config.sync_dirs.retain(|d| d != &dir);
+        } else {
+            println!(
+                "Directory {} is not empty. Keeping it...",
+                local_path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use super::ignore_changes::{detect_ignore_changes, untrack_files};
+use super::remote::{load_listing, with_dir_ignores};
+use super::{confirm, connect_db, construct_local_path, load_config, visit_local_files};
+use crate::ignore::parce_repo_ignore;
+use crate::{db, file_hash};
+
+use std::path::Path;
+
+pub async fn status(local_root: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = local_root.as_ref();
+    let config = load_config(local_root).await.map_err(|_| {
+        format!(
+            "Directory {} is not a correct dsync repo.",
+            local_root.display()
+        )
+    })?;
+    let ignore_filter = parce_repo_ignore(local_root, config.import_gitignore).await?;
+    let conn = connect_db(&config, local_root)?;
+    let files = db::list_files(&conn)?;
+    let placeholders = db::list_placeholders(&conn)?;
+    let updates = db::list_files_to_update(&conn)?;
+
+    let conflicts = db::list_conflicts(&conn)?;
+    if !conflicts.is_empty() {
+        println!("Unresolved conflicts:");
+        for conflict in conflicts.iter() {
+            let path = config.relative_path(&conflict.path);
+            match conflict.kind {
+                db::Conflict::REMOVED => println!("  deleted by remote: {}", path),
+                db::Conflict::REMOTE_COPY => println!("  conflicted copy:   {}", path),
+                db::Conflict::CASE_COLLISION => println!("  case collision:    {}", path),
+                _ => match conflict.copy_path {
+                    Some(ref copy_path) => {
+                        println!("  both modified:     {} (remote copy: {})", path, copy_path)
+                    }
+                    None => println!("  both modified:     {}", path),
+                },
+            }
+        }
+        println!("Use resolve command after fixing them.");
+    }
+
+    let mut copies: Vec<_> = db::list_conflicted_copies(&conn)?.into_iter().collect();
+    copies.sort_unstable();
+    if !copies.is_empty() {
+        println!("Conflicted copies made on Dropbox:");
+        for (copy, original) in copies.iter() {
+            println!(
+                "  {} (copy of {})",
+                config.relative_path(copy),
+                config.relative_path(original)
+            );
+        }
+    }
+
+    if !updates.is_empty() {
+        println!("Changes to be pushed:");
+        for update in updates.iter() {
+            let label = match update.operation {
+                db::FileUpdate::ADD => "new file:",
+                db::FileUpdate::REMOVE => "deleted: ",
+                _ => "modified:",
+            };
+            println!("  {} {}", label, config.relative_path(&update.path));
+        }
+    }
+
+    let mut paths: Vec<_> = files
+        .keys()
+        .filter(|p| !placeholders.contains_key(*p))
+        .collect();
+    paths.sort_unstable();
+    let mut not_staged = vec![];
+    for path in paths.into_iter() {
+        let local_path = construct_local_path(path, &config, local_root);
+        match file_hash(&local_path).await.ok() {
+            None => not_staged.push(("deleted: ", path)),
+            Some(hash) if hash[..] != files[path].hash[..] => not_staged.push(("modified:", path)),
+            _ => (),
+        }
+    }
+    if !not_staged.is_empty() {
+        println!("Changes not staged:");
+        for (label, path) in not_staged.into_iter() {
+            println!("  {} {}", label, config.relative_path(path));
+        }
+    }
+
+    let mut untracked: Vec<_> = visit_local_files(local_root, &config, local_root, &ignore_filter)
+        .await?
+        .into_iter()
+        .map(|(_, path)| path)
+        .filter(|path| !files.contains_key(path))
+        .collect();
+    untracked.sort_unstable();
+    if !untracked.is_empty() {
+        println!("Untracked files:");
+        for path in untracked.iter() {
+            println!("  {}", config.relative_path(path));
+        }
+    }
+
+    let remote_files = if db::has_cursor(&conn)? {
+        load_listing(&config, &conn, local_root, &ignore_filter)?.1
+    } else {
+        vec![]
+    };
+    let ignore_filter = with_dir_ignores(&ignore_filter, &config, &conn, local_root)?;
+    if db::has_cursor(&conn)? {
+        let files_by_lower = db::list_files_by_lower(&conn)?;
+        let mut fetched = vec![];
+        let mut remote_paths = std::collections::HashSet::new();
+        for info in remote_files.iter() {
+            let (path, hash) = match (&info.path_display, &info.content_hash) {
+                (Some(path), Some(hash)) => (path, hash),
+                _ => continue,
+            };
+            let path_lower = db::path_lower(path);
+            match files_by_lower.get(&path_lower) {
+                None => fetched.push(("new file:", path)),
+                Some(file) if hex::decode(hash).ok().as_ref() != Some(&file.hash) => {
+                    fetched.push(("modified:", path))
+                }
+                Some(file) if file.path != *path => fetched.push(("renamed: ", path)),
+                _ => (),
+            }
+            remote_paths.insert(path_lower);
+        }
+        for path in files.keys() {
+            if config.is_selected(path)
+                && !remote_paths.contains(&db::path_lower(path))
+                && !ignore_filter.is_ignored(&config.repo_path(path), false)
+            {
+                fetched.push(("deleted: ", path));
+            }
+        }
+        fetched.sort_unstable_by(|a, b| a.1.cmp(b.1));
+        if !fetched.is_empty() {
+            println!("Changes fetched from remote:");
+            for (label, path) in fetched.into_iter() {
+                println!("  {} {}", label, config.relative_path(path));
+            }
+        }
+    }
+
+    if let Some(changes) = detect_ignore_changes(&ignore_filter, &config, &conn, &remote_files)? {
+        changes.report(&config);
+        let mut accepted = changes.included.is_empty();
+        if !changes.ignored.is_empty() {
+            if confirm("Untrack the newly ignored files keeping the local copies?").await? {
+                untrack_files(&changes.ignored, &config, local_root, &conn).await?;
+            } else {
+                accepted = false;
+            }
+        }
+        if !changes.included.is_empty() {
+            println!("Use pull command to download the newly included files.");
+        }
+        if accepted {
+            changes.save(&conn)?;
+        }
+    }
+
+    if !placeholders.is_empty() {
+        println!(
+            "{} files are placeholders. Use fetch command to download them.",
+            placeholders.len()
+        );
+    }
+
+    Ok(())
+}
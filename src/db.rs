@@ -30,34 +30,195 @@ impl FileUpdate {
     }
 }
 
-pub fn connect(root: impl AsRef<std::path::Path>) -> Result<Connection> {
+pub struct Placeholder {
+    pub path: String,
+    pub rev: Option<String>,
+    pub size: i64,
+}
+
+impl Placeholder {
+    pub fn new(path: String, rev: Option<String>, size: i64) -> Self {
+        Placeholder { path, rev, size }
+    }
+}
+
+/// A file left conflicted by pull. `copy_path` is the local path of the remote copy if any.
+pub struct Conflict {
+    pub path: String,
+    pub kind: u8,
+    pub copy_path: Option<String>,
+}
+
+impl Conflict {
+    /// The conflict markers are written into the working file.
+    pub const MARKERS: u8 = 1;
+    /// The remote file is saved next to the working file.
+    pub const COPY: u8 = 2;
+    /// The file is remotely removed but locally modified.
+    pub const REMOVED: u8 = 3;
+    /// Dropbox made a conflicted copy of the file.
+    pub const REMOTE_COPY: u8 = 4;
+    /// The remote file or directory differs only in case from another local one.
+    pub const CASE_COLLISION: u8 = 5;
+
+    pub fn new(path: String, kind: u8, copy_path: Option<String>) -> Self {
+        Conflict {
+            path,
+            kind,
+            copy_path,
+        }
+    }
+}
+
+/// An entry of the saved remote listing. `info` is a JSON serialized `FileInfo`.
+pub enum RemoteEntry {
+    File { path_lower: String, info: String },
+    Dir { path_lower: String, path: String },
+    Deleted { path_lower: String },
+}
+
+/// Dropbox paths are case-insensitive. The tracked files are identified by this form of the path.
+pub fn path_lower(path: &str) -> String {
+    path.to_lowercase()
+}
+
+const CREATE_FILES: &str = "create table if not exists files (
+     id integer primary key autoincrement,
+     path_lower text not null unique,
+     path text not null,
+     hash blob not null
+ )";
+
+/// Opens the DB of the repo, creating the tables if needed.
+/// Also returns the paths no longer tracked by the upgrade of an old DB. See `migrate_files`.
+pub fn connect(root: impl AsRef<std::path::Path>) -> Result<(Connection, Vec<String>)> {
     let mut path = root.as_ref().to_owned();
     path.push(DB_NAME);
-    let conn = Connection::open(&path)?;
+    let mut conn = Connection::open(&path)?;
+
+    conn.execute(CREATE_FILES, NO_PARAMS)?;
 
     conn.execute(
-        "create table if not exists files (
-             id integer primary key autoincrement,
+        "create table if not exists updates (
              path text not null unique,
-             hash blob not null
+             operation int1 not null
          )",
         NO_PARAMS,
     )?;
 
     conn.execute(
-        "create table if not exists updates (
+        "create table if not exists placeholders (
              path text not null unique,
-             operation int1 not null
+             rev text,
+             size integer not null
+         )",
+        NO_PARAMS,
+    )?;
+
+    conn.execute(
+        "create table if not exists conflicts (
+             path text not null unique,
+             kind integer not null,
+             copy_path text
+         )",
+        NO_PARAMS,
+    )?;
+
+    conn.execute(
+        "create table if not exists conflicted_copies (
+             path text not null unique,
+             original text not null
+         )",
+        NO_PARAMS,
+    )?;
+
+    conn.execute(
+        "create table if not exists remote_files (
+             path_lower text not null unique,
+             info text not null
+         )",
+        NO_PARAMS,
+    )?;
+
+    conn.execute(
+        "create table if not exists remote_dirs (
+             path_lower text not null unique,
+             path text not null
          )",
         NO_PARAMS,
     )?;
 
-    Ok(conn)
+    conn.execute(
+        "create table if not exists remote_ignores (
+             dir text not null unique,
+             hash text not null,
+             content blob not null
+         )",
+        NO_PARAMS,
+    )?;
+
+    conn.execute(
+        "create table if not exists ignore_rules (
+             hash text not null,
+             rules text not null
+         )",
+        NO_PARAMS,
+    )?;
+
+    conn.execute(
+        "create table if not exists cursors (
+             root text not null unique,
+             cursor text not null
+         )",
+        NO_PARAMS,
+    )?;
+
+    let dropped = migrate_files(&mut conn)?;
+    Ok((conn, dropped))
+}
+
+/// Rebuilds the `files` table made before it was keyed on `path_lower`.
+/// Of the files differing only in case, the one with the smallest path in byte order is kept.
+/// The others are removed from all the tables and returned.
+fn migrate_files(conn: &mut Connection) -> Result<Vec<String>> {
+    let migrated = conn
+        .prepare("select count(*) from pragma_table_info('files') where name = 'path_lower'")?
+        .query_row(NO_PARAMS, |row| row.get::<_, i64>(0))?
+        > 0;
+    if migrated {
+        return Ok(vec![]);
+    }
+
+    let tx = conn.transaction()?;
+    let files = tx
+        .prepare("select path, hash from files order by path")?
+        .query_map(NO_PARAMS, |row| Ok(FileData::new(row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>>>()?;
+    tx.execute("drop table files", NO_PARAMS)?;
+    tx.execute(CREATE_FILES, NO_PARAMS)?;
+    let mut dropped = vec![];
+    for file in files.into_iter() {
+        let inserted = tx.execute(
+            "insert or ignore into files (path_lower, path, hash) values (?1, ?2, ?3)",
+            params!(path_lower(&file.path), &file.path, &file.hash),
+        )?;
+        if inserted == 0 {
+            for (table, column) in PATH_COLUMNS.iter() {
+                tx.execute(
+                    &format!("delete from {} where {} = ?", table, column),
+                    params!(&file.path),
+                )?;
+            }
+            dropped.push(file.path);
+        }
+    }
+    tx.commit()?;
+    Ok(dropped)
 }
 
 pub fn find_file(conn: &Connection, path: &str) -> Result<FileData> {
-    conn.prepare("select path, hash from files where path = ?1")?
-        .query_row(params!(path), |row| {
+    conn.prepare("select path, hash from files where path_lower = ?1")?
+        .query_row(params!(path_lower(path)), |row| {
             Ok(FileData::new(row.get(0)?, row.get(1)?))
         })
 }
@@ -70,10 +231,21 @@ pub fn list_files(conn: &Connection) -> Result<HashMap<String, FileData>> {
         .collect()
 }
 
+/// Lists the tracked files by `path_lower`.
+pub fn list_files_by_lower(conn: &Connection) -> Result<HashMap<String, FileData>> {
+    conn.prepare("select path_lower, path, hash from files")?
+        .query_map(NO_PARAMS, |row| {
+            Ok((row.get(0)?, FileData::new(row.get(1)?, row.get(2)?)))
+        })?
+        .collect()
+}
+
+/// Records the file. The display form of the path is replaced if it differs only in case.
 pub fn upsert_file(conn: &Connection, file: &FileData) -> Result<()> {
     conn.execute(
-        "insert into files (path, hash) values (?1, ?2) on conflict (path) do update set hash=excluded.hash",
+        "insert into files (path_lower, path, hash) values (?1, ?2, ?3) on conflict (path_lower) do update set path=excluded.path, hash=excluded.hash",
         params!(
+            path_lower(&file.path),
             &file.path,
             &file.hash,
         ),
@@ -83,8 +255,16 @@ pub fn upsert_file(conn: &Connection, file: &FileData) -> Result<()> {
 }
 
 pub fn delete_file_entry(conn: &Connection, path: &str) -> Result<()> {
-    conn.execute("delete from files where path = ?", params!(path))?;
+    conn.execute(
+        "delete from files where path_lower = ?",
+        params!(path_lower(path)),
+    )?;
     conn.execute("delete from updates where path = ?", params!(path))?;
+    conn.execute("delete from placeholders where path = ?", params!(path))?;
+    conn.execute(
+        "delete from conflicted_copies where path = ?",
+        params!(path),
+    )?;
     Ok(())
 }
 
@@ -93,8 +273,9 @@ pub fn upsert_files(conn: &mut Connection, files: &[FileData]) -> Result<()> {
 
     for file in files.into_iter() {
         tx.execute(
-            "insert into files (path, hash) values (?1, ?2) on conflict (path) do update set hash=excluded.hash",
+            "insert into files (path_lower, path, hash) values (?1, ?2, ?3) on conflict (path_lower) do update set path=excluded.path, hash=excluded.hash",
             params!(
+                path_lower(&file.path),
                 &file.path,
                 &file.hash,
             ),
@@ -105,6 +286,44 @@ pub fn upsert_files(conn: &mut Connection, files: &[FileData]) -> Result<()> {
     Ok(())
 }
 
+/// The tables and their columns holding the paths of the tracked files.
+const PATH_COLUMNS: [(&str, &str); 6] = [
+    ("files", "path"),
+    ("updates", "path"),
+    ("placeholders", "path"),
+    ("conflicts", "path"),
+    ("conflicted_copies", "path"),
+    ("conflicted_copies", "original"),
+];
+
+/// Changes the display form of the tracked file `from` to `to`, which differ only in case.
+pub fn rename_file_entry(conn: &Connection, from: &str, to: &str) -> Result<()> {
+    for (table, column) in PATH_COLUMNS.iter() {
+        conn.execute(
+            &format!("update {0} set {1} = ?2 where {1} = ?1", table, column),
+            params!(from, to),
+        )?;
+    }
+    Ok(())
+}
+
+/// Changes the display form of the directory `from` to `to` for all the tracked files under it.
+/// They differ only in case.
+pub fn rename_dir_entries(conn: &Connection, from: &str, to: &str) -> Result<()> {
+    let from = format!("{}/", from.trim_end_matches('/'));
+    let to = format!("{}/", to.trim_end_matches('/'));
+    for (table, column) in PATH_COLUMNS.iter() {
+        conn.execute(
+            &format!(
+                "update {0} set {1} = ?2 || substr({1}, length(?1) + 1) where substr({1}, 1, length(?1)) = ?1",
+                table, column
+            ),
+            params!(&from, &to),
+        )?;
+    }
+    Ok(())
+}
+
 pub fn add_update(conn: &Connection, fileupdate: &FileUpdate) -> Result<()> {
     conn.execute(
         "insert or replace into updates (path, operation) values (?1, ?2)",
@@ -128,13 +347,18 @@ pub fn add_update_list(conn: &mut Connection, fileupdates: &[FileUpdate]) -> Res
 }
 
 pub fn list_files_to_update(conn: &Connection) -> Result<Vec<FileUpdate>> {
-    conn.prepare("select path, operation from updates order")?
+    conn.prepare("select path, operation from updates order by path")?
         .query_map(NO_PARAMS, |row| {
             Ok(FileUpdate::new(row.get(0)?, row.get(1)?))
         })?
         .collect()
 }
 
+pub fn delete_update(conn: &Connection, path: &str) -> Result<()> {
+    conn.execute("delete from updates where path = ?", params!(path))?;
+    Ok(())
+}
+
 pub fn clear_all_files_to_update(conn: &Connection) -> Result<()> {
     conn.execute("delete from updates", NO_PARAMS)?;
     Ok(())
@@ -153,3 +377,194 @@ pub fn clear_files_to_update(conn: &mut Connection, paths: &[&str]) -> Result<()
     tx.commit()?;
     Ok(())
 }
+
+pub fn upsert_placeholder(conn: &Connection, placeholder: &Placeholder) -> Result<()> {
+    conn.execute(
+        "insert or replace into placeholders (path, rev, size) values (?1, ?2, ?3)",
+        params!(&placeholder.path, &placeholder.rev, &placeholder.size),
+    )?;
+    Ok(())
+}
+
+pub fn delete_placeholder(conn: &Connection, path: &str) -> Result<()> {
+    conn.execute("delete from placeholders where path = ?", params!(path))?;
+    Ok(())
+}
+
+pub fn list_placeholders(conn: &Connection) -> Result<HashMap<String, Placeholder>> {
+    conn.prepare("select path, rev, size from placeholders")?
+        .query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get(0)?,
+                Placeholder::new(row.get(0)?, row.get(1)?, row.get(2)?),
+            ))
+        })?
+        .collect()
+}
+
+pub fn upsert_conflict(conn: &Connection, conflict: &Conflict) -> Result<()> {
+    conn.execute(
+        "insert or replace into conflicts (path, kind, copy_path) values (?1, ?2, ?3)",
+        params!(&conflict.path, &conflict.kind, &conflict.copy_path),
+    )?;
+    Ok(())
+}
+
+pub fn delete_conflict(conn: &Connection, path: &str) -> Result<()> {
+    conn.execute("delete from conflicts where path = ?", params!(path))?;
+    Ok(())
+}
+
+pub fn list_conflicts(conn: &Connection) -> Result<Vec<Conflict>> {
+    conn.prepare("select path, kind, copy_path from conflicts order by path")?
+        .query_map(NO_PARAMS, |row| {
+            Ok(Conflict::new(row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect()
+}
+
+pub fn upsert_conflicted_copy(conn: &Connection, path: &str, original: &str) -> Result<()> {
+    conn.execute(
+        "insert or replace into conflicted_copies (path, original) values (?1, ?2)",
+        params!(path, original),
+    )?;
+    Ok(())
+}
+
+/// Lists the conflicted copies made by Dropbox with their originals.
+pub fn list_conflicted_copies(conn: &Connection) -> Result<HashMap<String, String>> {
+    conn.prepare("select path, original from conflicted_copies")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+/// Saves the remote entries under `root` with the cursor to continue listing.
+/// If `reset` is true, the entries saved before are discarded.
+pub fn save_remote_entries(
+    conn: &mut Connection,
+    root: &str,
+    entries: &[RemoteEntry],
+    cursor: Option<&str>,
+    reset: bool,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    if reset {
+        delete_remote_entries(&tx, root)?;
+        tx.execute("delete from cursors where root = ?1", params!(root))?;
+    }
+
+    for entry in entries.iter() {
+        match entry {
+            RemoteEntry::File { path_lower, info } => {
+                tx.execute(
+                    "insert or replace into remote_files (path_lower, info) values (?1, ?2)",
+                    params!(path_lower, info),
+                )?;
+            }
+            RemoteEntry::Dir { path_lower, path } => {
+                tx.execute(
+                    "insert or replace into remote_dirs (path_lower, path) values (?1, ?2)",
+                    params!(path_lower, path),
+                )?;
+            }
+            RemoteEntry::Deleted { path_lower } => {
+                delete_remote_entries(&tx, path_lower)?;
+            }
+        }
+    }
+
+    if let Some(cursor) = cursor {
+        tx.execute(
+            "insert or replace into cursors (root, cursor) values (?1, ?2)",
+            params!(root, cursor),
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Deletes the saved remote entry at `path_lower` and all the entries under it.
+fn delete_remote_entries(conn: &Connection, path_lower: &str) -> Result<()> {
+    let children = format!("{}/", path_lower.trim_end_matches('/'));
+    for table in ["remote_files", "remote_dirs"].iter() {
+        conn.execute(
+            &format!(
+                "delete from {} where path_lower = ?1 or substr(path_lower, 1, length(?2)) = ?2",
+                table
+            ),
+            params!(path_lower, &children),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn list_remote_files(conn: &Connection) -> Result<Vec<String>> {
+    conn.prepare("select info from remote_files")?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect()
+}
+
+pub fn find_remote_file(conn: &Connection, path_lower: &str) -> Result<String> {
+    conn.prepare("select info from remote_files where path_lower = ?1")?
+        .query_row(params!(path_lower), |row| row.get(0))
+}
+
+pub fn list_remote_dirs(conn: &Connection) -> Result<Vec<String>> {
+    conn.prepare("select path from remote_dirs")?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect()
+}
+
+/// Saves the content of the remote ignore file in `dir`, which is relative to the repo root.
+pub fn upsert_remote_ignore(
+    conn: &Connection,
+    dir: &str,
+    hash: &str,
+    content: &[u8],
+) -> Result<()> {
+    conn.execute(
+        "insert or replace into remote_ignores (dir, hash, content) values (?1, ?2, ?3)",
+        params!(dir, hash, content),
+    )?;
+    Ok(())
+}
+
+pub fn delete_remote_ignore(conn: &Connection, dir: &str) -> Result<()> {
+    conn.execute("delete from remote_ignores where dir = ?1", params!(dir))?;
+    Ok(())
+}
+
+/// Lists the saved remote ignore files as `(dir, hash, content)` ordered by `dir`.
+pub fn list_remote_ignores(conn: &Connection) -> Result<Vec<(String, String, Vec<u8>)>> {
+    conn.prepare("select dir, hash, content from remote_ignores order by dir")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect()
+}
+
+/// Finds the ignore rules saved by `save_ignore_rules` as `(hash, rules)`.
+pub fn find_ignore_rules(conn: &Connection) -> Result<(String, String)> {
+    conn.prepare("select hash, rules from ignore_rules")?
+        .query_row(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+}
+
+pub fn save_ignore_rules(conn: &Connection, hash: &str, rules: &str) -> Result<()> {
+    conn.execute("delete from ignore_rules", NO_PARAMS)?;
+    conn.execute(
+        "insert into ignore_rules (hash, rules) values (?1, ?2)",
+        params!(hash, rules),
+    )?;
+    Ok(())
+}
+
+pub fn find_cursor(conn: &Connection, root: &str) -> Result<String> {
+    conn.prepare("select cursor from cursors where root = ?1")?
+        .query_row(params!(root), |row| row.get(0))
+}
+
+pub fn has_cursor(conn: &Connection) -> Result<bool> {
+    conn.prepare("select count(*) from cursors")?
+        .query_row(NO_PARAMS, |row| row.get::<_, i64>(0))
+        .map(|n| n > 0)
+}
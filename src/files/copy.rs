@@ -0,0 +1,91 @@
+use super::list_folder::Entry;
+use crate::request_json_response_json;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct RelocationResult {
+    metadata: Entry,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+enum BatchEntry {
+    Success { success: Entry },
+    Failure { failure: Value },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+enum BatchStatus {
+    AsyncJobId { async_job_id: String },
+    InProgress,
+    Complete { entries: Vec<BatchEntry> },
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub async fn copy(from_path: &str, to_path: &str, token: &str) -> Result<Entry, Box<dyn Error>> {
+    let json = json!({
+        "from_path": from_path,
+        "to_path": to_path,
+    });
+    let RelocationResult { metadata } =
+        request_json_response_json("files/copy_v2", token, None, &json).await?;
+    Ok(metadata)
+}
+
+/// Moves the file on the server side. `to_path` may differ from `from_path` only in case.
+pub async fn move_file(
+    from_path: &str,
+    to_path: &str,
+    token: &str,
+) -> Result<Entry, Box<dyn Error>> {
+    let json = json!({
+        "from_path": from_path,
+        "to_path": to_path,
+    });
+    let RelocationResult { metadata } =
+        request_json_response_json("files/move_v2", token, None, &json).await?;
+    Ok(metadata)
+}
+
+/// Copies each `(from_path, to_path)` pair on the server side.
+/// The results are returned in the same order as `paths`.
+pub async fn copy_batch(
+    paths: &[(String, String)],
+    token: &str,
+) -> Result<Vec<Result<Entry, String>>, Box<dyn Error>> {
+    let entries: Vec<_> = paths
+        .iter()
+        .map(|(from_path, to_path)| json!({ "from_path": from_path, "to_path": to_path }))
+        .collect();
+    let json = json!({ "entries": entries });
+    let mut status = request_json_response_json("files/copy_batch_v2", token, None, &json).await?;
+    let mut job_id = None;
+
+    loop {
+        match status {
+            BatchStatus::Complete { entries } => {
+                return Ok(entries
+                    .into_iter()
+                    .map(|e| match e {
+                        BatchEntry::Success { success } => Ok(success),
+                        BatchEntry::Failure { failure } => Err(failure.to_string()),
+                    })
+                    .collect());
+            }
+            BatchStatus::AsyncJobId { async_job_id } => {
+                job_id = Some(async_job_id);
+            }
+            BatchStatus::InProgress => {}
+        }
+
+        tokio::time::delay_for(POLL_INTERVAL).await;
+        let json = json!({ "async_job_id": job_id.as_ref().ok_or("No job id returned")? });
+        status =
+            request_json_response_json("files/copy_batch/check_v2", token, None, &json).await?;
+    }
+}
@@ -39,25 +39,78 @@ pub async fn list_folder(
     {
         json.insert("limit".to_owned(), limit.into());
     }
+    let (entries, _) = list_all(json, token).await?;
+    Ok(entries)
+}
+
+/// Lists all the entries under `path` recursively.
+/// The returned cursor can be passed to `list_folder_continue` to get the changes afterwards.
+pub async fn list_folder_recursive(
+    path: &str,
+    token: &str,
+) -> Result<(Vec<Entry>, String), Box<dyn Error>> {
+    let mut json = HashMap::new();
+    json.insert("path".to_owned(), Value::String(path.to_owned()));
+    json.insert("recursive".to_owned(), Value::Bool(true));
+    list_all(json, token).await
+}
+
+/// Lists all the entries under `path` recursively, including the deleted ones.
+pub async fn list_folder_with_deleted(
+    path: &str,
+    token: &str,
+) -> Result<Vec<Entry>, Box<dyn Error>> {
+    let mut json = HashMap::new();
+    json.insert("path".to_owned(), Value::String(path.to_owned()));
+    json.insert("recursive".to_owned(), Value::Bool(true));
+    json.insert("include_deleted".to_owned(), Value::Bool(true));
+    let (entries, _) = list_all(json, token).await?;
+    Ok(entries)
+}
+
+/// Lists the changes after `cursor` was returned.
+pub async fn list_folder_continue(
+    cursor: &str,
+    token: &str,
+) -> Result<(Vec<Entry>, String), Box<dyn Error>> {
+    let first = continue_folder(cursor, token).await?;
+    collect_pages(first, token).await
+}
+
+async fn list_all(
+    json: HashMap<String, Value>,
+    token: &str,
+) -> Result<(Vec<Entry>, String), Box<dyn Error>> {
+    let first = request_json_response_json("/files/list_folder", token, None, &json).await?;
+    collect_pages(first, token).await
+}
+
+async fn collect_pages(
+    first: ListFolder,
+    token: &str,
+) -> Result<(Vec<Entry>, String), Box<dyn Error>> {
     let ListFolder {
         mut entries,
         mut cursor,
         mut has_more,
-    } = request_json_response_json("/files/list_folder", token, None, &json).await?;
+    } = first;
     while has_more {
-        let mut json = HashMap::new();
-        json.insert("cursor".to_owned(), cursor.clone());
-
         let ListFolder {
             entries: e2,
             cursor: c2,
             has_more: h2,
-        } = request_json_response_json("/files/list_folder/continue", token, None, &json).await?;
+        } = continue_folder(&cursor, token).await?;
         cursor = c2;
         has_more = h2;
         entries.extend(e2.into_iter());
     }
-    Ok(entries)
+    Ok((entries, cursor))
+}
+
+async fn continue_folder(cursor: &str, token: &str) -> Result<ListFolder, Box<dyn Error>> {
+    let mut json = HashMap::new();
+    json.insert("cursor".to_owned(), cursor.to_owned());
+    request_json_response_json("/files/list_folder/continue", token, None, &json).await
 }
 
 #[cfg(test)]
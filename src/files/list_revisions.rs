@@ -0,0 +1,57 @@
+use super::FileInfo;
+use crate::request_json_response_json;
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Revisions {
+    pub is_deleted: bool,
+    pub server_deleted: Option<String>,
+    pub entries: Vec<FileInfo>,
+    #[serde(default)]
+    pub has_more: bool,
+}
+
+/// Lists the revisions of a file, newest first.
+/// `path` may also be a file id of the form `id:...`.
+/// With `before_rev`, only the revisions older than it are listed.
+pub async fn list_revisions(
+    path: &str,
+    limit: u32,
+    before_rev: Option<&str>,
+    token: &str,
+) -> Result<Revisions, Box<dyn Error>> {
+    let mode = if path.starts_with("id:") {
+        "id"
+    } else {
+        "path"
+    };
+    let mut json = json!({
+        "path": path,
+        "mode": { ".tag": mode },
+        "limit": limit,
+    });
+    if let Some(before_rev) = before_rev {
+        json["before_rev"] = before_rev.into();
+    }
+    request_json_response_json("files/list_revisions", token, None, &json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn list_revisions_test() {
+        let token = crate::get_token().await.unwrap();
+        let revisions = list_revisions("/dsync_test/milky-way-nasa.jpg", 10, None, &token)
+            .await
+            .unwrap();
+        assert_eq!(revisions.is_deleted, false);
+        assert_eq!(
+            "485291fa0ee50c016982abbfa943957bcd231aae0492ccbaa22c58e3997b35e0",
+            revisions.entries[0].content_hash.as_ref().unwrap()
+        );
+    }
+}
@@ -1,11 +1,16 @@
+pub mod copy;
 pub mod delete;
 pub mod download;
 pub mod get_metadata;
 pub mod list_folder;
+pub mod list_revisions;
+pub mod restore;
 pub mod upload;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
 
-#[derive(Deserialize, Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
 pub struct FileInfo {
     pub name: String,
     pub id: String,
@@ -17,3 +22,78 @@ pub struct FileInfo {
     pub path_display: Option<String>,
     pub content_hash: Option<String>,
 }
+
+/// An error returned by the Dropbox API.
+/// `tags` are the nested `.tag`s of the error, e.g. `["path", "not_found"]`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ApiError {
+    pub tags: Vec<String>,
+    pub summary: String,
+}
+
+impl ApiError {
+    /// Parses the error response. Returns `None` if it is not an error of the API.
+    pub fn parse(data: &str) -> Option<ApiError> {
+        let json: serde_json::Value = serde_json::from_str(data).ok()?;
+        let summary = json.get("error_summary")?.as_str()?.to_owned();
+        let mut tags = vec![];
+        let mut error = json.get("error");
+        while let Some(tag) = error.and_then(|e| e.get(".tag")).and_then(|t| t.as_str()) {
+            tags.push(tag.to_owned());
+            error = error.and_then(|e| e.get(tag));
+        }
+        Some(ApiError { tags, summary })
+    }
+
+    /// Checks the tags like `path/not_found`.
+    pub fn is(&self, tags: &str) -> bool {
+        let tags: Vec<_> = tags.split('/').collect();
+        self.tags.len() >= tags.len() && self.tags.iter().zip(tags.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.summary)
+    }
+}
+
+impl Error for ApiError {}
+
+/// Checks if the error is `ApiError` with the tags like `path/not_found`.
+pub fn is_api_error(e: &(dyn Error + 'static), tags: &str) -> bool {
+    e.downcast_ref::<ApiError>().map_or(false, |e| e.is(tags))
+}
+
+/// Converts the response body of a failed request into an error.
+pub(crate) fn response_error(data: String) -> Box<dyn Error> {
+    match ApiError::parse(&data) {
+        Some(e) => e.into(),
+        None => data.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_error_test() {
+        let e = ApiError::parse(
+            r#"{"error_summary": "path/not_found/..", "error": {".tag": "path", "path": {".tag": "not_found"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(e.tags, vec!["path", "not_found"]);
+        assert_eq!(e.is("path/not_found"), true);
+        assert_eq!(e.is("path"), true);
+        assert_eq!(e.is("reset"), false);
+        assert_eq!(e.to_string(), "path/not_found/..");
+
+        let e = ApiError::parse(r#"{"error_summary": "reset/..", "error": {".tag": "reset"}}"#);
+        assert_eq!(e.map(|e| e.is("reset")), Some(true));
+        // A path containing the tag is not an error of the tag.
+        assert_eq!(ApiError::parse(r#"{"name": "not_found.txt"}"#), None);
+        let e: Box<dyn Error> = "path/not_found".into();
+        assert_eq!(is_api_error(&*e, "path/not_found"), false);
+    }
+}
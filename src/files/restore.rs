@@ -0,0 +1,12 @@
+use super::FileInfo;
+use crate::request_json_response_json;
+use serde_json::json;
+use std::error::Error;
+
+pub async fn restore(path: &str, rev: &str, token: &str) -> Result<FileInfo, Box<dyn Error>> {
+    let json = json!({
+        "path": path,
+        "rev": rev,
+    });
+    request_json_response_json("files/restore", token, None, &json).await
+}
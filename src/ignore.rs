@@ -1,43 +1,411 @@
-use std::fmt::Debug;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::path::{Path, PathBuf};
 
+/// A path relative to the repo root, separated by `/` without leading or trailing `/`.
+/// The local and remote paths are converted into it before matched against the ignore rules,
+/// so that a rule means the same on both sides.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct RepoPath(String);
+
+impl RepoPath {
+    /// Normalizes the path relative to the repo root. Empty and `.` components are dropped.
+    pub fn new(relative_path: &str) -> RepoPath {
+        RepoPath(
+            relative_path
+                .split('/')
+                .filter(|c| *c != "" && *c != ".")
+                .collect::<Vec<_>>()
+                .join("/"),
+        )
+    }
+
+    pub fn root() -> RepoPath {
+        RepoPath(String::new())
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the strict ancestors from the nearest to the root, excluding the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = RepoPath> + '_ {
+        self.0
+            .rmatch_indices('/')
+            .map(move |(i, _)| RepoPath(self.0[..i].to_owned()))
+    }
+
+    pub fn join(&self, name: &str) -> RepoPath {
+        if self.is_root() {
+            RepoPath::new(name)
+        } else {
+            RepoPath::new(&format!("{}/{}", self.0, name))
+        }
+    }
+}
+
+impl Display for RepoPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone)]
 pub struct Ignore {
-    ignores: Vec<FileMatchExpr>,
+    ignores: Vec<Rule>,
+    /// Indices of the rules matching a name at any level like `node_modules`, by the name.
+    by_name: HashMap<Vec<char>, Vec<usize>>,
+    /// Indices of the other rules.
+    others: Vec<usize>,
+    /// The rules given for a single command, which take precedence over the ignore files.
+    overrides: Vec<Rule>,
+    builtin: Rule,
+}
+
+/// A line of the ignore file. A line starting with `!` re-includes the matching files.
+#[derive(Clone)]
+pub struct Rule {
+    expr: FileMatchExpr,
+    /// Components of the directory containing the ignore file, relative to the repo root.
+    base: Vec<Vec<char>>,
+    pub negated: bool,
+    /// The ignore file containing the rule.
+    pub source: String,
+    /// The line number starting from 1. 0 for the built-in rule.
+    pub line: usize,
+    pub pattern: String,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.pattern)
+    }
+}
+
+impl Rule {
+    /// Parses a line of the ignore file. Returns `None` for blank and comment lines.
+    fn parse(line: &str, base: &RepoPath, source: &str, line_number: usize) -> Option<Rule> {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with('#') {
+            return None;
+        }
+
+        // Trailing spaces are ignored unless escaped.
+        let mut end = line.len();
+        while line[..end].ends_with(' ') && !line[..end - 1].ends_with('\\') {
+            end -= 1;
+        }
+        let line = &line[..end];
+        let pattern = line.to_owned();
+
+        let (line, negated) = if line.starts_with('!') {
+            (&line[1..], true)
+        } else {
+            (line, false)
+        };
+        if line == "" {
+            return None;
+        }
+
+        Some(Rule {
+            expr: FileMatchExpr::compile(line),
+            base: if base.is_root() {
+                vec![]
+            } else {
+                FileMatchExpr::compile_target(base.as_str()).0
+            },
+            negated,
+            source: source.to_owned(),
+            line: line_number,
+            pattern,
+        })
+    }
+
+    fn match_file_compiled(&self, targ: &[Vec<char>], is_dir: bool) -> bool {
+        let n = self.base.len();
+        targ.len() > n
+            && targ[..n] == self.base[..]
+            && self.expr.match_components(&targ[n..], is_dir)
+    }
 }
 
 pub const IGNORE_FILE: &str = ".dsyncignore";
 
-async fn _parce_ignore(filename: &str) -> Result<Ignore, Box<dyn std::error::Error>> {
-    let mut ignores = if let Ok(data) = tokio::fs::read(filename).await {
-        let lines = String::from_utf8(data).map_err(|_| format!("invalid .dsyncignore file"))?;
-        lines
-            .split('\n')
-            .filter(|l| *l != "" && !l.starts_with('#'))
-            .map(|l| l.trim())
-            .map(FileMatchExpr::compile)
-            .collect()
-    } else {
-        vec![]
-    };
+fn parse_rules(
+    data: Vec<u8>,
+    base: &RepoPath,
+    source: &str,
+) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+    let lines = String::from_utf8(data).map_err(|_| format!("invalid ignore file {}", source))?;
+    Ok(lines
+        .split('\n')
+        .enumerate()
+        .filter_map(|(i, l)| Rule::parse(l, base, source, i + 1))
+        .collect())
+}
+
+/// The exclude file private to the repo, which is never synced.
+/// Not `.dsync/info/exclude`, since `.dsync` mirrors the repo with the base copies
+/// and the base copy of a remote file `info/exclude` would be taken for it.
+pub const EXCLUDE_FILE: &str = ".dsync_info/exclude";
+pub const GITIGNORE_FILE: &str = ".gitignore";
+
+/// The ignore file of the user, `$XDG_CONFIG_HOME/dsync/ignore` or `~/.config/dsync/ignore`.
+pub fn global_ignore_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+    Some(config_home.join("dsync").join("ignore"))
+}
 
-    ignores.push(FileMatchExpr::compile(".dsync*"));
+async fn _parce_ignore(filename: &str) -> Result<Ignore, Box<dyn std::error::Error>> {
+    let mut ignore = Ignore::new();
+    ignore.add_ignore_file(&RepoPath::root(), filename).await?;
+    Ok(ignore)
+}
 
-    Ok(Ignore { ignores })
+/// Reads the global ignore file only, for a repo which does not exist locally yet.
+pub async fn parce_global_ignore() -> Result<Ignore, Box<dyn std::error::Error>> {
+    let mut ignore = Ignore::new();
+    if let Some(path) = global_ignore_path() {
+        ignore.add_ignore_file(&RepoPath::root(), path).await?;
+    }
+    Ok(ignore)
 }
 
-pub async fn parce_ignore() -> Result<Ignore, Box<dyn std::error::Error>> {
-    _parce_ignore(IGNORE_FILE).await
+/// Reads the ignore files of the repo at `local_root`. From the lowest precedence,
+/// the global ignore file, `EXCLUDE_FILE`, `.gitignore` if `import_gitignore` and `.dsyncignore`.
+pub async fn parce_repo_ignore(
+    local_root: &Path,
+    import_gitignore: bool,
+) -> Result<Ignore, Box<dyn std::error::Error>> {
+    let root = RepoPath::root();
+    let mut ignore = Ignore::new();
+    if let Some(path) = global_ignore_path() {
+        ignore.add_ignore_file(&root, path).await?;
+    }
+    ignore
+        .add_ignore_file(&root, local_root.join(EXCLUDE_FILE))
+        .await?;
+    if import_gitignore {
+        ignore
+            .add_ignore_file(&root, local_root.join(GITIGNORE_FILE))
+            .await?;
+    }
+    ignore
+        .add_ignore_file(&root, local_root.join(IGNORE_FILE))
+        .await?;
+    Ok(ignore)
 }
 
 impl Ignore {
-    pub fn is_ignored(&self, file: &str) -> bool {
-        let compiled = FileMatchExpr::compile_target(file);
+    fn new() -> Ignore {
+        Ignore {
+            ignores: vec![],
+            by_name: HashMap::new(),
+            others: vec![],
+            overrides: vec![],
+            builtin: Rule::parse(".dsync*", &RepoPath::root(), "<built-in>", 0).unwrap(),
+        }
+    }
+
+    /// Adds the rules of the ignore file in `dir`. `source` names the file in messages.
+    /// They only apply to the files under `dir` and take precedence over the rules added before.
+    pub fn add_rules(
+        &mut self,
+        dir: &RepoPath,
+        source: &str,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for rule in parse_rules(data, dir, source)?.into_iter() {
+            self.push_rule(rule);
+        }
+        Ok(())
+    }
+
+    /// Adds the rules relative to the repo root which take precedence over all the other rules,
+    /// like `--exclude` of a command. They are not included in `rules_text`.
+    pub fn add_overrides(
+        &mut self,
+        source: &str,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.overrides
+            .extend(parse_rules(data, &RepoPath::root(), source)?.into_iter());
+        Ok(())
+    }
+
+    fn push_rule(&mut self, rule: Rule) {
+        let index = self.ignores.len();
+        match rule.expr.fast {
+            FastPath::Name(ref name) => self.by_name.entry(name.clone()).or_default().push(index),
+            _ => self.others.push(index),
+        }
+        self.ignores.push(rule);
+    }
+
+    /// Serializes the rules as lines of the directory and the pattern separated by a tab,
+    /// from the lowest precedence. The built-in rule is not included.
+    pub fn rules_text(&self) -> String {
         self.ignores
             .iter()
-            .any(|i| i.match_file_compiled(&compiled))
+            .map(|rule| {
+                let base: Vec<String> = rule.base.iter().map(|c| c.iter().collect()).collect();
+                format!("{}\t{}\n", base.join("/"), rule.pattern)
+            })
+            .collect()
+    }
+
+    /// Restores the rules serialized by `rules_text`. `source` names them in messages.
+    pub fn from_rules_text(text: &str, source: &str) -> Ignore {
+        let mut ignore = Ignore::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut parts = line.splitn(2, '\t');
+            if let (Some(base), Some(pattern)) = (parts.next(), parts.next()) {
+                if let Some(rule) = Rule::parse(pattern, &RepoPath::new(base), source, i + 1) {
+                    ignore.push_rule(rule);
+                }
+            }
+        }
+        ignore
+    }
+
+    /// Adds the rules of the ignore file at `path` if it exists, like `add_rules`.
+    pub async fn add_ignore_file(
+        &mut self,
+        dir: &RepoPath,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        if let Ok(data) = tokio::fs::read(path).await {
+            self.add_rules(dir, &path.to_string_lossy(), data)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the path. The last matching rule wins.
+    /// A file in an excluded directory stays excluded even if a negated rule matches it.
+    /// `is_dir` tells if the path is a directory, for the rules ending with `/`.
+    pub fn is_ignored(&self, file: &RepoPath, is_dir: bool) -> bool {
+        self.matching_rule(file, is_dir)
+            .map_or(false, |r| !r.negated)
+    }
+
+    /// Returns the rule deciding whether the path is ignored like `is_ignored`,
+    /// which is a negated one if the path is re-included.
+    /// If a parent directory is excluded, the rule excluding it is returned.
+    pub fn matching_rule(&self, file: &RepoPath, is_dir: bool) -> Option<&Rule> {
+        let compiled = FileMatchExpr::compile_target(file.as_str());
+        let n = compiled.0.len();
+        for i in 1..n {
+            match self.last_match(&compiled.0[..i], true) {
+                Some(rule) if !rule.negated => return Some(rule),
+                _ => (),
+            }
+        }
+        self.last_match(&compiled.0, is_dir)
+    }
+
+    fn last_match(&self, targ: &[Vec<char>], is_dir: bool) -> Option<&Rule> {
+        if self.builtin.match_file_compiled(targ, is_dir) {
+            return Some(&self.builtin);
+        }
+        if let Some(rule) = self
+            .overrides
+            .iter()
+            .rev()
+            .find(|r| r.match_file_compiled(targ, is_dir))
+        {
+            return Some(rule);
+        }
+
+        // Only the rules for the name can match among the rules indexed by name.
+        let mut found = targ
+            .last()
+            .and_then(|name| self.by_name.get(name))
+            .and_then(|indices| {
+                indices
+                    .iter()
+                    .rev()
+                    .find(|i| self.ignores[**i].match_file_compiled(targ, is_dir))
+            })
+            .copied();
+        for i in self.others.iter().rev() {
+            if found.map_or(false, |f| *i < f) {
+                break;
+            }
+            if self.ignores[*i].match_file_compiled(targ, is_dir) {
+                found = Some(*i);
+                break;
+            }
+        }
+        found.map(|i| &self.ignores[i])
     }
 }
 
-pub struct FileMatchExpr(Vec<Piece<Vec<Piece<char>>>>);
+/// A pattern of gitignore syntax.
+#[derive(Clone)]
+pub struct FileMatchExpr {
+    expr: Vec<Piece<Vec<Piece<CharExpr>>>>,
+    /// The pattern ends with `/` and matches only directories.
+    dir_only: bool,
+    fast: FastPath,
+}
+
+/// A cheaper way to match the common patterns.
+#[derive(Clone)]
+enum FastPath {
+    /// A name without wildcards matching at any level, e.g. `node_modules`.
+    Name(Vec<char>),
+    /// A suffix of the name matching at any level, e.g. `*.log`.
+    Suffix(Vec<char>),
+    /// The literal components at the beginning of a pattern relative to the root,
+    /// e.g. `docs/api` of `/docs/api/*.html`. The path must start with them.
+    Prefix(Vec<Vec<char>>),
+}
+
+impl FastPath {
+    fn new(expr: &[Piece<Vec<Piece<CharExpr>>>]) -> FastPath {
+        fn literal(component: &[Piece<CharExpr>]) -> Option<Vec<char>> {
+            component
+                .iter()
+                .map(|p| match p {
+                    Piece::Piece(CharExpr::Char(c)) => Some(*c),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        match expr {
+            [Piece::Any, Piece::Piece(component)] => {
+                if let Some(name) = literal(component) {
+                    return FastPath::Name(name);
+                }
+                if let [Piece::Any, rest @ ..] = &component[..] {
+                    if let Some(suffix) = literal(rest) {
+                        return FastPath::Suffix(suffix);
+                    }
+                }
+                FastPath::Prefix(vec![])
+            }
+            _ => FastPath::Prefix(
+                expr.iter()
+                    .map(|p| match p {
+                        Piece::Piece(component) => literal(component),
+                        Piece::Any => None,
+                    })
+                    .take_while(|c| c.is_some())
+                    .map(|c| c.unwrap())
+                    .collect(),
+            ),
+        }
+    }
+}
 pub struct StrToMatch(Vec<Vec<char>>);
 
 impl FileMatchExpr {
@@ -45,53 +413,135 @@ impl FileMatchExpr {
         StrToMatch(targ.split('/').map(|s| s.chars().collect()).collect())
     }
 
+    /// Compiles the pattern. As in gitignore, a pattern with `/` at the beginning or in the middle
+    /// is relative to the root, and the other patterns match at any level.
     pub fn compile(mut line: &str) -> FileMatchExpr {
         let mut expr = vec![];
-        let mut dir = false;
+
+        let dir_only = line.ends_with('/');
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
 
         if line.starts_with('/') {
             line = &line[1..];
-        } else {
+        } else if !line.contains('/') {
             expr.push(Piece::Any);
         }
 
-        if line.ends_with('/') {
-            line = &line[..line.len() - 1];
-            dir = true;
-        }
-
-        expr.extend(line.split('/').map(|p| {
-            match p {
-                "**" => Piece::Any,
-                s => Piece::Piece(
-                    s.chars()
-                        .map(|c| match c {
-                            '*' => Piece::Any,
-                            c => Piece::Piece(c),
-                        })
-                        .collect(),
-                ),
-            }
+        expr.extend(line.split('/').map(|p| match p {
+            "**" => Piece::Any,
+            s => Piece::Piece(compile_component(s)),
         }));
 
-        if dir {
-            expr.push(Piece::Piece(vec![Piece::Piece('?'), Piece::Any]));
-            expr.push(Piece::Any);
+        let fast = FastPath::new(&expr);
+        FileMatchExpr {
+            expr,
+            dir_only,
+            fast,
         }
-        FileMatchExpr(expr)
     }
 
+    /// Checks the file at the path relative to the root.
+    /// A pattern ending with `/` matches the files under the directory.
     pub fn match_file(&self, file: &str) -> bool {
-        self.0
-            .match_expr(&file.split('/').map(|s| s.chars().collect()).collect())
+        let targ = Self::compile_target(file).0;
+        if self.dir_only {
+            (1..targ.len()).any(|n| self.match_components(&targ[..n], true))
+        } else {
+            self.match_components(&targ, false)
+        }
+    }
+
+    pub fn match_file_compiled(&self, targ: &StrToMatch, is_dir: bool) -> bool {
+        self.match_components(&targ.0, is_dir)
+    }
+
+    fn match_components(&self, targ: &[Vec<char>], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        match self.fast {
+            FastPath::Name(ref name) => targ.last() == Some(name),
+            FastPath::Suffix(ref suffix) => targ.last().map_or(false, |n| n.ends_with(suffix)),
+            FastPath::Prefix(ref prefix) => {
+                targ.len() >= prefix.len()
+                    && targ[..prefix.len()] == prefix[..]
+                    && match_pieces(&self.expr[prefix.len()..], &targ[prefix.len()..])
+            }
+        }
+    }
+}
+
+/// Compiles a path component with `*`, `?`, `[...]` and backslash escapes.
+fn compile_component(s: &str) -> Vec<Piece<CharExpr>> {
+    let chars: Vec<_> = s.chars().collect();
+    let mut pieces = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                pieces.push(Piece::Piece(CharExpr::Char(chars[i])));
+            }
+            '*' => pieces.push(Piece::Any),
+            '?' => pieces.push(Piece::Piece(CharExpr::AnyChar)),
+            '[' => match compile_class(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    pieces.push(Piece::Piece(class));
+                    i += len;
+                }
+                // An unclosed bracket is a literal.
+                None => pieces.push(Piece::Piece(CharExpr::Char('['))),
+            },
+            c => pieces.push(Piece::Piece(CharExpr::Char(c))),
+        }
+        i += 1;
     }
+    pieces
+}
+
+/// Compiles a character class following `[`. Returns the class and the number of chars used.
+fn compile_class(chars: &[char]) -> Option<(CharExpr, usize)> {
+    let negated = chars.first() == Some(&'!') || chars.first() == Some(&'^');
+    let start = if negated { 1 } else { 0 };
+    let mut ranges = vec![];
+    let mut i = start;
+    loop {
+        let mut c = *chars.get(i)?;
+        // `]` right after `[` is a member.
+        if c == ']' && i > start {
+            return Some((CharExpr::Class(negated, ranges), i + 1));
+        }
+        if c == '\\' {
+            i += 1;
+            c = *chars.get(i)?;
+        }
 
-    pub fn match_file_compiled(&self, targ: &StrToMatch) -> bool {
-        self.0.match_expr(&targ.0)
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).map_or(false, |e| *e != ']') {
+            i += 2;
+            let mut end = chars[i];
+            if end == '\\' {
+                i += 1;
+                end = *chars.get(i)?;
+            }
+            ranges.push((c, end));
+        } else {
+            ranges.push((c, c));
+        }
+        i += 1;
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum CharExpr {
+    Char(char),
+    AnyChar,
+    /// Negated or not, and the ranges of the characters.
+    Class(bool, Vec<(char, char)>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 enum Piece<T: Debug + Eq + PartialEq> {
     Any,
     Piece(T),
@@ -109,47 +559,63 @@ impl Match for char {
     }
 }
 
+impl Match for CharExpr {
+    type MatchType = char;
+    fn match_expr(&self, targ: &Self::MatchType) -> bool {
+        match self {
+            CharExpr::Char(c) => c == targ,
+            CharExpr::AnyChar => true,
+            CharExpr::Class(negated, ranges) => {
+                ranges.iter().any(|(a, b)| a <= targ && targ <= b) != *negated
+            }
+        }
+    }
+}
+
 impl<S: Match + Debug + Eq + PartialEq> Match for Vec<Piece<S>> {
     type MatchType = Vec<S::MatchType>;
     fn match_expr(&self, targ: &Vec<S::MatchType>) -> bool {
-        let n = targ.len();
-        let m = self.len();
-        let mut results = vec![vec![None; m + 1]; n + 1];
-
-        fn match_body<T: Match + Debug + Eq + PartialEq>(
-            targ: &[T::MatchType],
-            expr: &[Piece<T>],
-            i_targ: usize,
-            i_expr: usize,
-            result_table: &mut Vec<Vec<Option<bool>>>,
-        ) -> bool {
-            if let Some(res) = result_table[i_targ][i_expr] {
-                return res;
-            }
+        match_pieces(self, targ)
+    }
+}
 
-            let result = if i_expr == expr.len() {
-                i_targ == targ.len()
-            } else {
-                match &expr[i_expr] {
-                    Piece::Piece(p) => {
-                        i_targ != targ.len()
-                            && p.match_expr(&targ[i_targ])
-                            && match_body(targ, expr, i_targ + 1, i_expr + 1, result_table)
-                    }
-                    Piece::Any => {
-                        (i_targ != targ.len()
-                            && match_body(targ, expr, i_targ + 1, i_expr, result_table))
-                            || match_body(targ, expr, i_targ, i_expr + 1, result_table)
-                    }
-                }
-            };
+/// Matches the sequence with the wildcards without allocation.
+/// On a mismatch, the last `Any` is retried to consume one more element.
+fn match_pieces<S: Match + Debug + Eq + PartialEq>(
+    expr: &[Piece<S>],
+    targ: &[S::MatchType],
+) -> bool {
+    let mut i_expr = 0;
+    let mut i_targ = 0;
+    // The position after the last `Any` and the position in `targ` it is retried from.
+    let mut retry = None;
 
-            result_table[i_targ][i_expr] = Some(result);
-            result
+    while i_targ < targ.len() {
+        match expr.get(i_expr) {
+            Some(Piece::Any) => {
+                i_expr += 1;
+                retry = Some((i_expr, i_targ));
+                continue;
+            }
+            Some(Piece::Piece(p)) if p.match_expr(&targ[i_targ]) => {
+                i_expr += 1;
+                i_targ += 1;
+                continue;
+            }
+            _ => (),
         }
 
-        match_body(targ, &self, 0, 0, &mut results)
+        match retry {
+            Some((e, t)) => {
+                i_expr = e;
+                i_targ = t + 1;
+                retry = Some((e, t + 1));
+            }
+            None => return false,
+        }
     }
+
+    expr[i_expr..].iter().all(|p| *p == Piece::Any)
 }
 
 #[cfg(test)]
@@ -180,7 +646,8 @@ mod tests {
         let pat = FileMatchExpr::compile("hoge/fuga.rs");
         assert_eq!(pat.match_file("hoge.rs"), false);
         assert_eq!(pat.match_file("hoge/fuga.rs"), true);
-        assert_eq!(pat.match_file("piyo/hoge/fuga.rs"), true);
+        // A pattern with a slash in the middle is relative to the root.
+        assert_eq!(pat.match_file("piyo/hoge/fuga.rs"), false);
         assert_eq!(pat.match_file("hoge/fuga/piyo.rs"), false);
 
         let pat = FileMatchExpr::compile("/**/*.r?");
@@ -203,6 +670,18 @@ mod tests {
         assert_eq!(pat.match_file("hoge/some/nested/piyo.rs"), true);
     }
 
+    #[test]
+    fn repo_path_test() {
+        assert_eq!(RepoPath::new("/hoge/./fuga/"), RepoPath::new("hoge/fuga"));
+        assert_eq!(RepoPath::new("hoge//fuga").as_str(), "hoge/fuga");
+        assert_eq!(RepoPath::new("/"), RepoPath::root());
+        assert_eq!(RepoPath::root().join("hoge").as_str(), "hoge");
+        assert_eq!(
+            RepoPath::new("a/b/c").ancestors().collect::<Vec<_>>(),
+            vec![RepoPath::new("a/b"), RepoPath::new("a")]
+        );
+    }
+
     #[tokio::test]
     async fn parse_ignore_test() {
         let filename = "dsync_ignore_test";
@@ -219,13 +698,478 @@ foo/**",
         .unwrap();
 
         let ignores = _parce_ignore(filename).await.unwrap();
-        assert_eq!(ignores.is_ignored("comment"), false);
-        assert_eq!(ignores.is_ignored("#comment"), false);
-        assert_eq!(ignores.is_ignored(""), false);
-        assert_eq!(ignores.is_ignored("aaaa/hoge/bbbb"), true);
-        assert_eq!(ignores.is_ignored("some/nested/folder/foo/bar"), true);
-        assert_eq!(ignores.is_ignored("some/nested/folder/fuga/piyo"), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("comment"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("#comment"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new(""), false), false);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("aaaa/hoge/bbbb"), false),
+            true
+        );
+        assert_eq!(ignores.is_ignored(&RepoPath::new("foo/bar"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("some/nested/folder/foo/bar"), false),
+            false
+        );
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("some/nested/folder/fuga/piyo"), false),
+            false
+        );
+
+        tokio::fs::remove_file(filename).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn negation_test() {
+        let filename = "dsync_ignore_negation_test";
+        tokio::fs::write(
+            filename,
+            "build/*
+!build/release-notes.md
+*.log
+!important.log
+/cache
+!/cache/keep
+!.dsyncconfig",
+        )
+        .await
+        .unwrap();
+
+        let ignores = _parce_ignore(filename).await.unwrap();
+        assert_eq!(ignores.is_ignored(&RepoPath::new("build/a.o"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("build/release-notes.md"), false),
+            false
+        );
+        let rule = ignores
+            .matching_rule(&RepoPath::new("build/release-notes.md"), false)
+            .unwrap();
+        assert_eq!((rule.line, rule.negated), (2, true));
+        assert_eq!(rule.pattern, "!build/release-notes.md");
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("build/sub/release-notes.md"), false),
+            true
+        );
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a.log"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("logs/important.log"), false),
+            false
+        );
+        // The parent directory is excluded.
+        assert_eq!(ignores.is_ignored(&RepoPath::new("cache"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("cache/keep"), false),
+            true
+        );
+        // The built-in rule is applied last.
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new(".dsyncconfig"), false),
+            true
+        );
+
+        tokio::fs::remove_file(filename).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn nested_ignore_test() {
+        let filename = "dsync_ignore_nested_test";
+        tokio::fs::write(filename, "*.log\n/tmp\n").await.unwrap();
+
+        let mut ignores = _parce_ignore(filename).await.unwrap();
+        ignores
+            .add_rules(
+                &RepoPath::new("docs"),
+                "test",
+                b"/build\n!keep.log\n".to_vec(),
+            )
+            .unwrap();
+        ignores
+            .add_rules(&RepoPath::new("docs/api"), "test", b"*.html\n".to_vec())
+            .unwrap();
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a.log"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("docs/keep.log"), false),
+            false
+        );
+        assert_eq!(ignores.is_ignored(&RepoPath::new("keep.log"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("docs/build/a.md"), false),
+            true
+        );
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("build/a.md"), false),
+            false
+        );
+        assert_eq!(ignores.is_ignored(&RepoPath::new("docs/tmp"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("/tmp/a"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("docs/api/index.html"), false),
+            true
+        );
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("docs/index.html"), false),
+            false
+        );
+        assert_eq!(ignores.is_ignored(&RepoPath::new("docs/api"), true), false);
+
+        tokio::fs::remove_file(filename).await.unwrap();
+    }
+
+    #[test]
+    fn rules_text_test() {
+        let mut ignores = Ignore::new();
+        ignores
+            .add_rules(
+                &RepoPath::root(),
+                "test",
+                b"*.log\n# comment\nfoo\\ \n".to_vec(),
+            )
+            .unwrap();
+        ignores
+            .add_rules(
+                &RepoPath::new("docs"),
+                "test",
+                b"/build/\n!keep.log\n".to_vec(),
+            )
+            .unwrap();
+        let text = ignores.rules_text();
+        assert_eq!(text, "\t*.log\n\tfoo\\ \ndocs\t/build/\ndocs\t!keep.log\n");
+
+        let restored = Ignore::from_rules_text(&text, "saved");
+        assert_eq!(restored.rules_text(), text);
+        for (path, is_dir) in [
+            ("a.log", false),
+            ("docs/keep.log", false),
+            ("docs/build", true),
+            ("docs/build/a.md", false),
+            ("build/a.md", false),
+            ("foo ", false),
+        ]
+        .iter()
+        {
+            let path = RepoPath::new(path);
+            assert_eq!(
+                restored.is_ignored(&path, *is_dir),
+                ignores.is_ignored(&path, *is_dir)
+            );
+        }
+        assert_eq!(restored.is_ignored(&RepoPath::new("foo "), false), true);
+    }
+
+    #[test]
+    fn overrides_test() {
+        let mut ignores = Ignore::new();
+        ignores
+            .add_rules(&RepoPath::root(), "test", b"*.log\n".to_vec())
+            .unwrap();
+        ignores
+            .add_overrides("<command line>", b"!keep.log\n/docs/*.md\n".to_vec())
+            .unwrap();
+        ignores
+            .add_rules(&RepoPath::new("docs"), "test", b"!*.md\n".to_vec())
+            .unwrap();
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a.log"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("keep.log"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("docs/a.md"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("docs/api/a.md"), false),
+            false
+        );
+        assert_eq!(
+            ignores
+                .matching_rule(&RepoPath::new("docs/a.md"), false)
+                .unwrap()
+                .to_string(),
+            "<command line>:2:/docs/*.md"
+        );
+        assert_eq!(ignores.rules_text(), "\t*.log\ndocs\t!*.md\n");
+    }
+
+    #[tokio::test]
+    async fn parse_repo_ignore_test() {
+        let root = Path::new("dsync_repo_ignore_test");
+        tokio::fs::create_dir_all(root.join(".dsync_info"))
+            .await
+            .unwrap();
+        tokio::fs::write(root.join(EXCLUDE_FILE), "*.tmp\n")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join(GITIGNORE_FILE), "*.o\n!keep.tmp\n")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join(IGNORE_FILE), "!main.o\n")
+            .await
+            .unwrap();
+
+        let ignores = parce_repo_ignore(root, true).await.unwrap();
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a.tmp"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("keep.tmp"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a.o"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("main.o"), false), false);
+
+        let ignores = parce_repo_ignore(root, false).await.unwrap();
+        assert_eq!(ignores.is_ignored(&RepoPath::new("keep.tmp"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a.o"), false), false);
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[test]
+    fn gitignore_syntax_test() {
+        let pat = FileMatchExpr::compile("*.[ch]");
+        assert_eq!(pat.match_file("src/a.c"), true);
+        assert_eq!(pat.match_file("a.h"), true);
+        assert_eq!(pat.match_file("a.o"), false);
+
+        let pat = FileMatchExpr::compile("log[!0-9a-f].txt");
+        assert_eq!(pat.match_file("logx.txt"), true);
+        assert_eq!(pat.match_file("log3.txt"), false);
+        assert_eq!(pat.match_file("logb.txt"), false);
+
+        let pat = FileMatchExpr::compile("[]a]");
+        assert_eq!(pat.match_file("]"), true);
+        assert_eq!(pat.match_file("a"), true);
+        assert_eq!(pat.match_file("b"), false);
+
+        let pat = FileMatchExpr::compile("a[b");
+        assert_eq!(pat.match_file("a[b"), true);
+
+        let pat = FileMatchExpr::compile("\\*star\\?");
+        assert_eq!(pat.match_file("*star?"), true);
+        assert_eq!(pat.match_file("a-star!"), false);
+
+        let pat = FileMatchExpr::compile("doc/frotz");
+        assert_eq!(pat.match_file("doc/frotz"), true);
+        assert_eq!(pat.match_file("a/doc/frotz"), false);
+
+        let pat = FileMatchExpr::compile("frotz");
+        assert_eq!(pat.match_file("a/doc/frotz"), true);
+    }
+
+    #[tokio::test]
+    async fn gitignore_file_test() {
+        let filename = "dsync_ignore_syntax_test";
+        tokio::fs::write(
+            filename,
+            "\\#hash
+\\!bang
+trailing  
+escaped\\ 
+out/
+!out/keep
+bin/
+!bin/*.sh",
+        )
+        .await
+        .unwrap();
+
+        let ignores = _parce_ignore(filename).await.unwrap();
+        assert_eq!(ignores.is_ignored(&RepoPath::new("#hash"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("!bang"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("bang"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("trailing"), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("escaped "), false), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("escaped"), false), false);
+        // Directory-only rules do not match files.
+        assert_eq!(ignores.is_ignored(&RepoPath::new("out"), false), false);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("out"), true), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("a/out"), true), true);
+        assert_eq!(ignores.is_ignored(&RepoPath::new("out/a"), false), true);
+        // The parent directory is excluded.
+        assert_eq!(ignores.is_ignored(&RepoPath::new("out/keep"), false), true);
+        assert_eq!(
+            ignores.is_ignored(&RepoPath::new("bin/run.sh"), false),
+            true
+        );
 
         tokio::fs::remove_file(filename).await.unwrap();
     }
+
+    /// The matcher before the fast paths, as the reference of the tests and the benchmark.
+    fn match_table<T: Match + Debug + Eq + PartialEq>(
+        expr: &[Piece<T>],
+        targ: &[T::MatchType],
+    ) -> bool {
+        fn match_body<T: Match + Debug + Eq + PartialEq>(
+            targ: &[T::MatchType],
+            expr: &[Piece<T>],
+            i_targ: usize,
+            i_expr: usize,
+            result_table: &mut Vec<Vec<Option<bool>>>,
+        ) -> bool {
+            if let Some(res) = result_table[i_targ][i_expr] {
+                return res;
+            }
+
+            let result = if i_expr == expr.len() {
+                i_targ == targ.len()
+            } else {
+                match &expr[i_expr] {
+                    Piece::Piece(p) => {
+                        i_targ != targ.len()
+                            && p.match_expr(&targ[i_targ])
+                            && match_body(targ, expr, i_targ + 1, i_expr + 1, result_table)
+                    }
+                    Piece::Any => {
+                        (i_targ != targ.len()
+                            && match_body(targ, expr, i_targ + 1, i_expr, result_table))
+                            || match_body(targ, expr, i_targ, i_expr + 1, result_table)
+                    }
+                }
+            };
+
+            result_table[i_targ][i_expr] = Some(result);
+            result
+        }
+
+        let mut results = vec![vec![None; expr.len() + 1]; targ.len() + 1];
+        match_body(targ, expr, 0, 0, &mut results)
+    }
+
+    /// Checks the components by the reference matcher, trying every rule.
+    fn reference_last_match<'a>(
+        ignores: &'a Ignore,
+        targ: &[Vec<char>],
+        is_dir: bool,
+    ) -> Option<&'a Rule> {
+        std::iter::once(&ignores.builtin)
+            .chain(ignores.ignores.iter().rev())
+            .find(|r| {
+                let n = r.base.len();
+                (is_dir || !r.expr.dir_only)
+                    && targ.len() > n
+                    && targ[..n] == r.base[..]
+                    && match_table(&r.expr.expr, &targ[n..].to_vec())
+            })
+    }
+
+    const TEST_PATTERNS: &str = "hoge/
+/fuga/piyo
+foo/**
+hoge/fuga.rs
+/**/*.r?
+hoge/**/fuga/*.rs
+*.log
+!important.log
+node_modules
+build/*
+!build/release-notes.md
+*.[ch]
+log[!0-9a-f].txt
+doc/frotz
+.DS_Store
+*~
+";
+
+    fn test_paths(n: usize) -> Vec<(RepoPath, bool)> {
+        let dirs = [
+            "",
+            "hoge",
+            "src",
+            "src/fuga",
+            "docs/api",
+            "build",
+            "node_modules/x",
+            "a/doc",
+        ];
+        let names = [
+            "a.rs",
+            "fuga.rs",
+            "b.log",
+            "important.log",
+            "c.c",
+            "logx.txt",
+            "frotz",
+            "d.md~",
+            "release-notes.md",
+            "piyo",
+            ".DS_Store",
+            "node_modules",
+        ];
+        (0..n)
+            .map(|i| {
+                let dir = dirs[i % dirs.len()];
+                let name = names[(i / dirs.len()) % names.len()];
+                let path = RepoPath::new(&format!("{}/{}{}", dir, i / 97, name));
+                let plain = RepoPath::new(&format!("{}/{}", dir, name));
+                if i % 2 == 0 {
+                    (plain, i % 3 == 0)
+                } else {
+                    (path, false)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fast_path_test() {
+        let mut ignores = Ignore::new();
+        ignores
+            .add_rules(&RepoPath::root(), "test", TEST_PATTERNS.as_bytes().to_vec())
+            .unwrap();
+        ignores
+            .add_rules(&RepoPath::new("src"), "test", b"*.c\n!/fuga\n".to_vec())
+            .unwrap();
+
+        for (path, is_dir) in test_paths(2000).iter() {
+            let targ = FileMatchExpr::compile_target(path.as_str()).0;
+            for n in 1..=targ.len() {
+                let is_dir = n < targ.len() || *is_dir;
+                assert_eq!(
+                    ignores
+                        .last_match(&targ[..n], is_dir)
+                        .map(|r| (&r.source, r.line)),
+                    reference_last_match(&ignores, &targ[..n], is_dir).map(|r| (&r.source, r.line)),
+                    "{}",
+                    path
+                );
+            }
+        }
+    }
+
+    /// Compares the time to check many paths with the reference matcher.
+    /// Run with `cargo test --release bench_is_ignored -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_is_ignored() {
+        let mut ignores = Ignore::new();
+        let mut patterns = String::new();
+        for i in 0..3 {
+            patterns.push_str(&TEST_PATTERNS.replace("\n", &format!("{}\n", i)));
+        }
+        patterns.push_str(TEST_PATTERNS);
+        ignores
+            .add_rules(&RepoPath::root(), "bench", patterns.into_bytes())
+            .unwrap();
+        let paths = test_paths(100_000);
+
+        let start = std::time::Instant::now();
+        let fast = paths
+            .iter()
+            .filter(|(p, d)| ignores.is_ignored(p, *d))
+            .count();
+        let elapsed_fast = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let reference = paths
+            .iter()
+            .filter(|(p, d)| {
+                let targ = FileMatchExpr::compile_target(p.as_str()).0;
+                let n = targ.len();
+                (1..=n).any(|i| {
+                    reference_last_match(&ignores, &targ[..i], i < n || *d)
+                        .map_or(false, |r| !r.negated)
+                })
+            })
+            .count();
+        let elapsed_reference = start.elapsed();
+
+        assert_eq!(fast, reference);
+        println!(
+            "{} paths, {} rules: {:?} (reference {:?})",
+            paths.len(),
+            ignores.ignores.len(),
+            elapsed_fast,
+            elapsed_reference
+        );
+    }
 }
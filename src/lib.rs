@@ -89,6 +89,9 @@ where
         arg,
     )
     .await?;
+    if !response.status().is_success() {
+        return Err(files::response_error(response.text().await?));
+    }
     let result = response
         .headers()
         .get(RESULT_HEADER)
@@ -124,7 +127,7 @@ where
     T: serde::de::DeserializeOwned,
 {
     let data = request_json_response_text(api, access_token, headers, body_json).await?;
-    serde_json::from_str(&data).map_err(|_| data.into())
+    serde_json::from_str(&data).map_err(|_| files::response_error(data))
 }
 
 pub async fn request_blob_response_json<T>(
@@ -138,7 +141,7 @@ where
     T: serde::de::DeserializeOwned,
 {
     let data = request_blob_response_text(api, access_token, headers, arg, body).await?;
-    serde_json::from_str(&data).map_err(|_| data.into())
+    serde_json::from_str(&data).map_err(|_| files::response_error(data))
 }
 
 pub async fn request_blob_response_text(
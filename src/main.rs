@@ -14,20 +14,175 @@ struct Opts {
 #[derive(Clap)]
 enum SubCommand {
     Clone(CloneCommand),
-    Pull,
+    Pull(PullCommand),
+    Push(PushCommand),
+    Apply(ApplyCommand),
     Add(AddCommand),
     Init(InitCommand),
+    Cp(CpCommand),
+    Log(LogCommand),
+    Restore(RestoreCommand),
+    Rollback(RollbackCommand),
+    Reset(ResetCommand),
+    Checkout(CheckoutCommand),
+    Sparse(SparseCommand),
+    Fetch(FetchCommand),
+    Evict(EvictCommand),
+    Status,
+    Diff(DiffCommand),
+    Resolve(ResolveCommand),
+    Mergetool(MergetoolCommand),
+    CheckIgnore(CheckIgnoreCommand),
 }
 
 #[derive(Clap)]
 struct CloneCommand {
     remote_path: String,
     local_path: Option<String>,
+    /// Only clone this directory. Can be specified multiple times.
+    #[clap(long = "include", multiple_occurrences = true, number_of_values = 1)]
+    includes: Vec<String>,
+    /// Only record the remote files without downloading them
+    #[clap(long = "lazy")]
+    lazy: bool,
+    /// Also ignore the files matching the patterns in this file
+    #[clap(long = "ignore-file")]
+    ignore_file: Option<String>,
+    /// Also ignore the files matching this pattern. Can be specified multiple times.
+    #[clap(long = "exclude", multiple_occurrences = true, number_of_values = 1)]
+    excludes: Vec<String>,
+    /// Only print what would be done
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+#[derive(Clap)]
+struct PullCommand {
+    /// Use the remote state saved by the last fetch
+    #[clap(long = "no-fetch")]
+    no_fetch: bool,
+    /// How to handle the files changed both locally and remotely
+    #[clap(long = "strategy", possible_values = &["ours", "theirs", "both", "merge"])]
+    strategy: Option<String>,
+    /// Also ignore the files matching the patterns in this file
+    #[clap(long = "ignore-file")]
+    ignore_file: Option<String>,
+    /// Also ignore the files matching this pattern. Can be specified multiple times.
+    #[clap(long = "exclude", multiple_occurrences = true, number_of_values = 1)]
+    excludes: Vec<String>,
+    /// Only print what would be done
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+    /// Save the plan as JSON
+    #[clap(long = "plan-out")]
+    plan_out: Option<String>,
+}
+
+#[derive(Clap)]
+struct PushCommand {
+    /// Only print what would be done
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+    /// Save the plan as JSON
+    #[clap(long = "plan-out")]
+    plan_out: Option<String>,
+}
+
+#[derive(Clap)]
+struct SparseCommand {
+    #[clap(subcommand)]
+    subcmd: SparseSubCommand,
+}
+
+#[derive(Clap)]
+enum SparseSubCommand {
+    /// Select a directory to be synchronized
+    Add(SparseDirCommand),
+    /// Deselect a directory and remove its local copies
+    Remove(SparseDirCommand),
+    /// List the selected directories
+    List,
+}
+
+#[derive(Clap)]
+struct SparseDirCommand {
+    dir: String,
+}
+
+#[derive(Clap)]
+struct FetchCommand {
+    /// Placeholder files or directories to download.
+    /// Without them, the remote changes are fetched without touching the working tree.
+    local_paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct EvictCommand {
+    /// Files or directories to turn into placeholders
+    #[clap(required = true)]
+    local_paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct DiffCommand {
+    /// Show the fetched remote changes instead of the local changes
+    #[clap(long = "remote")]
+    remote: bool,
+    /// Files or directories to compare. Defaults to the whole repo.
+    local_paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct ResolveCommand {
+    /// Conflicted files or directories to mark as resolved
+    #[clap(required = true)]
+    local_paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct MergetoolCommand {
+    /// Tool name (meld, kdiff3 or vimdiff) or a command with {base}, {local}, {remote} and {merged}
+    #[clap(long = "tool")]
+    tool: Option<String>,
+    /// Conflicted files or directories to merge. Defaults to all.
+    local_paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct CheckIgnoreCommand {
+    /// Show the ignore file, line number and pattern of the matching rule
+    #[clap(short = 'v', long = "verbose")]
+    verbose: bool,
+    /// Also show the paths matching no rule
+    #[clap(short = 'n', long = "non-matching")]
+    non_matching: bool,
+    /// Files or directories to check
+    #[clap(required = true)]
+    local_paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct ApplyCommand {
+    /// Plan saved with --plan-out
+    plan_file: String,
 }
 
 #[derive(Clap)]
 struct AddCommand {
-    local_path: String,
+    /// Stage new, modified and deleted files in the whole repo
+    #[clap(short = 'A', long = "all")]
+    all: bool,
+    /// Stage only modified and deleted files
+    #[clap(short = 'u', long = "update")]
+    update: bool,
+    /// Also ignore the files matching the patterns in this file
+    #[clap(long = "ignore-file")]
+    ignore_file: Option<String>,
+    /// Also ignore the files matching this pattern. Can be specified multiple times.
+    #[clap(long = "exclude", multiple_occurrences = true, number_of_values = 1)]
+    excludes: Vec<String>,
+    /// Files, directories or glob patterns to stage
+    local_paths: Vec<String>,
 }
 
 #[derive(Clap)]
@@ -36,22 +191,218 @@ struct InitCommand {
     remote_path: String,
 }
 
+#[derive(Clap)]
+struct CpCommand {
+    /// Source paths followed by the destination
+    #[clap(required = true, min_values = 2)]
+    paths: Vec<String>,
+}
+
+#[derive(Clap)]
+struct LogCommand {
+    /// Local path or Dropbox file id (id:...)
+    target: String,
+    /// Maximum number of revisions to show
+    #[clap(short = 'n', long = "limit", default_value = "10")]
+    limit: u32,
+}
+
+#[derive(Clap)]
+struct RestoreCommand {
+    local_path: String,
+    /// Revision to restore
+    #[clap(long = "rev", conflicts_with = "before")]
+    rev: Option<String>,
+    /// Restore the latest revision at or before this time, e.g. 2020-05-01T12:00:00Z
+    #[clap(long = "before")]
+    before: Option<String>,
+    /// Only write the revision into the working file without restoring it on Dropbox
+    #[clap(long = "local-only")]
+    local_only: bool,
+}
+
+#[derive(Clap)]
+struct RollbackCommand {
+    /// Roll back to the state at this time, e.g. 2020-05-01T12:00:00Z
+    #[clap(long = "to")]
+    to: String,
+    /// Directory or file to roll back. Defaults to the whole repo.
+    local_path: Option<String>,
+    /// Apply the plan without confirmation
+    #[clap(short = 'y', long = "yes")]
+    yes: bool,
+}
+
+#[derive(Clap)]
+struct ResetCommand {
+    /// File or directory to unstage. Defaults to everything.
+    local_path: Option<String>,
+}
+
+#[derive(Clap)]
+struct CheckoutCommand {
+    /// Files or directories to discard local changes
+    #[clap(required = true, last = true)]
+    local_paths: Vec<String>,
+}
+
 async fn init(command: InitCommand) -> Result<(), Box<dyn std::error::Error>> {
     commands::init::init(&command.remote_path, &command.local_path).await
 }
 
 async fn add(command: AddCommand) -> Result<(), Box<dyn std::error::Error>> {
-    commands::add::add(&command.local_path, std::env::current_dir()?).await
+    commands::add::add(
+        &command.local_paths,
+        command.all,
+        command.update,
+        command.ignore_file.as_deref(),
+        &command.excludes,
+        std::env::current_dir()?,
+    )
+    .await
+}
+
+async fn cp(command: CpCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths = command.paths;
+    let dest = paths
+        .pop()
+        .ok_or_else(|| "destination must be specified".to_owned())?;
+    commands::cp::cp(&paths, &dest, std::env::current_dir()?, token).await
+}
+
+async fn log(command: LogCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::log::log(
+        &command.target,
+        command.limit,
+        std::env::current_dir()?,
+        token,
+    )
+    .await
+}
+
+async fn restore(command: RestoreCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::restore::restore(
+        &command.local_path,
+        command.rev.as_deref(),
+        command.before.as_deref(),
+        command.local_only,
+        std::env::current_dir()?,
+        token,
+    )
+    .await
+}
+
+async fn rollback(command: RollbackCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::rollback::rollback(
+        &command.to,
+        command.local_path.as_deref(),
+        command.yes,
+        std::env::current_dir()?,
+        token,
+    )
+    .await
+}
+
+async fn reset(command: ResetCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::reset::reset(command.local_path.as_deref(), std::env::current_dir()?).await
+}
+
+async fn checkout(command: CheckoutCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::checkout::checkout(&command.local_paths, std::env::current_dir()?).await
 }
 
-async fn pull(token: &str) -> Result<(), Box<dyn std::error::Error>> {
-    commands::pull::pull(std::env::current_dir()?, token).await
+async fn pull(command: PullCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::pull::pull(
+        std::env::current_dir()?,
+        command.no_fetch,
+        command.strategy.as_deref(),
+        command.ignore_file.as_deref(),
+        &command.excludes,
+        command.dry_run,
+        command.plan_out.as_deref(),
+        token,
+    )
+    .await
+}
+
+async fn push(command: PushCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::push::push(
+        std::env::current_dir()?,
+        command.dry_run,
+        command.plan_out.as_deref(),
+        token,
+    )
+    .await
+}
+
+async fn sparse(command: SparseCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let local_root = std::env::current_dir()?;
+    match command.subcmd {
+        SparseSubCommand::Add(command) => commands::sparse::add(&command.dir, local_root).await,
+        SparseSubCommand::Remove(command) => {
+            commands::sparse::remove(&command.dir, local_root).await
+        }
+        SparseSubCommand::List => commands::sparse::list(local_root).await,
+    }
+}
+
+async fn fetch(command: FetchCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::fetch::fetch(&command.local_paths, std::env::current_dir()?, token).await
+}
+
+async fn evict(command: EvictCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::evict::evict(&command.local_paths, std::env::current_dir()?).await
+}
+
+async fn status() -> Result<(), Box<dyn std::error::Error>> {
+    commands::status::status(std::env::current_dir()?).await
+}
+
+async fn diff(command: DiffCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::diff::diff(
+        &command.local_paths,
+        command.remote,
+        std::env::current_dir()?,
+    )
+    .await
+}
+
+async fn resolve(command: ResolveCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::resolve::resolve(&command.local_paths, std::env::current_dir()?).await
+}
+
+async fn mergetool(command: MergetoolCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::mergetool::mergetool(
+        &command.local_paths,
+        command.tool.as_deref(),
+        std::env::current_dir()?,
+    )
+    .await
+}
+
+async fn check_ignore(command: CheckIgnoreCommand) -> Result<(), Box<dyn std::error::Error>> {
+    commands::check_ignore::check_ignore(
+        &command.local_paths,
+        command.verbose,
+        command.non_matching,
+        std::env::current_dir()?,
+    )
+    .await
+}
+
+async fn apply(command: ApplyCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    commands::apply::apply(&command.plan_file, std::env::current_dir()?, token).await
 }
 
 async fn clone(command: CloneCommand, token: &str) -> Result<(), Box<dyn std::error::Error>> {
     let CloneCommand {
         remote_path,
         local_path,
+        includes,
+        lazy,
+        ignore_file,
+        excludes,
+        dry_run,
     } = command;
 
     let local_path = if let Some(path) = local_path {
@@ -71,7 +422,17 @@ async fn clone(command: CloneCommand, token: &str) -> Result<(), Box<dyn std::er
         }
     };
 
-    commands::clone::clone(&remote_path, local_path, token).await
+    commands::clone::clone(
+        &remote_path,
+        local_path,
+        &includes,
+        lazy,
+        ignore_file.as_deref(),
+        &excludes,
+        dry_run,
+        token,
+    )
+    .await
 }
 
 #[tokio::main]
@@ -81,8 +442,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match opts.subcmd {
         SubCommand::Clone(command) => clone(command, &token).await,
-        SubCommand::Pull => pull(&token).await,
+        SubCommand::Pull(command) => pull(command, &token).await,
+        SubCommand::Push(command) => push(command, &token).await,
+        SubCommand::Apply(command) => apply(command, &token).await,
+        SubCommand::Sparse(command) => sparse(command).await,
+        SubCommand::Fetch(command) => fetch(command, &token).await,
+        SubCommand::Evict(command) => evict(command).await,
+        SubCommand::Status => status().await,
+        SubCommand::Diff(command) => diff(command).await,
+        SubCommand::Resolve(command) => resolve(command).await,
+        SubCommand::Mergetool(command) => mergetool(command).await,
+        SubCommand::CheckIgnore(command) => check_ignore(command).await,
         SubCommand::Add(command) => add(command).await,
         SubCommand::Init(command) => init(command).await,
+        SubCommand::Cp(command) => cp(command, &token).await,
+        SubCommand::Log(command) => log(command, &token).await,
+        SubCommand::Restore(command) => restore(command, &token).await,
+        SubCommand::Rollback(command) => rollback(command, &token).await,
+        SubCommand::Reset(command) => reset(command).await,
+        SubCommand::Checkout(command) => checkout(command).await,
     }
 }